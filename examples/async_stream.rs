@@ -0,0 +1,43 @@
+//! Stream a completion through the async API, printing text as it arrives.
+//! Takes the same arguments as vocllm, with the prompt given through the `single` command:
+//!
+//!     cargo run --release --features tokio --example async_stream -- -m model.gguf -B llama single "Hello!"
+
+use std::io::Write;
+
+use clap::Parser;
+use vocllm::argsc::{CliArgs, Commands};
+use vocllm::async_api::{AsyncGenerator, StreamEvent};
+use vocllm::chat;
+use vocllm::llmcall::QuantizedTextGenerator;
+
+#[tokio::main]
+async fn main() {
+    let mut args = CliArgs::parse();
+    pretty_env_logger::init();
+    if let Err(e) = args.fix_options() {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+    let Commands::Single(parg) = &args.command else {
+        eprintln!("Give the prompt with the `single` command.");
+        std::process::exit(2);
+    };
+    let prompt = chat::make_prompt(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), &parg.prompt, None, args.normalize_prompt);
+
+    // Loading is blocking too, so it is moved off the async runtime's threads as well.
+    let g = tokio::task::block_in_place(|| QuantizedTextGenerator::from_args(&args));
+    let g = AsyncGenerator::new(g);
+
+    let mut events = g.stream(prompt);
+    while let Some(event) = events.recv().await {
+        match event {
+            StreamEvent::Text(text) => {
+                print!("{}", text);
+                let _ = std::io::stdout().flush();
+            },
+            StreamEvent::Done(Ok(c)) => println!("\n[{}, {} tokens]", c.finish_reason, c.generated_tokens),
+            StreamEvent::Done(Err(e)) => eprintln!("\nGeneration failed: {}", e)
+        }
+    }
+}
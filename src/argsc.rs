@@ -1,12 +1,18 @@
 //! A module to define, and derive CLI parser to obtain all settings and configuration data for the application.
 
 
-use std::{fs, path::PathBuf};
+use std::{borrow::Cow, fs, path::{Path, PathBuf}};
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
-use log::error;
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use log::{error, info, warn};
 
-use crate::chat::ChatTemplate;
+use crate::character::CharacterCard;
+use crate::chat::{ChatRole, ChatTemplate, OverflowPolicy, PromptFormat};
+use crate::llmcall::{read_gguf_metadata, DeviceSpec, SamplingParams};
+use crate::sampling::RngAlgo;
+use crate::source;
+use crate::tts;
 
 const DEFUALT_SYSTEM_PROMPT: &str = "
 You are a professional interactive AI assistant.
@@ -18,12 +24,13 @@ Your answers must be concise and correct. Never refuse to answer.
 
 #[derive(Debug, Parser)]
 #[command(version, about)]
+#[command(group(clap::ArgGroup::new("context").args(["context_file", "context_url"])))]
 pub struct CliArgs {
 	#[command(subcommand)]
 	pub command: Commands,
 	#[arg(short, long)]
-	/// Path to GGUF file to load.
-	pub model_path: String,
+	/// Path to GGUF file to load. Required by every command except `info`.
+	pub model_path: Option<String>,
 	#[arg(short = 'T', long)]
 	/// Path to HF tokeniser data file for the model. If not specified, will look for 'tokenizer.json' in same directory as model_path.
 	/// Ideally, this should be inferred from data within GGUF, however, as candle doesn't provide any methods for that yet, this is used as fallback.
@@ -34,29 +41,128 @@ pub struct CliArgs {
 	#[arg(long, default_value_t = 42)]
 	/// Seed value to use for generation. Important for reproducability.
 	pub seed: u64,
+	#[arg(long, default_value = "candle")]
+	/// Random number generator for sampling. `chacha8` and `chacha20` use a portable generator and sampler of our own,
+	/// so that the same seed picks the same tokens on every host (given the same logits; GPU kernels may still differ slightly).
+	pub rng: RngAlgo,
 	#[arg(long, default_value_t = 0.7)]
 	pub temperature: f64,
 	#[arg(long)]
 	pub top_p: Option<f64>,
+	#[arg(long)]
+	/// Tail-free sampling parameter z in (0, 1], applied after the repeat penalty. Discards the low-probability tail of the distribution.
+	pub tfs: Option<f32>,
+	#[arg(long)]
+	/// Locally typical sampling parameter p in (0, 1], applied after tail-free sampling, before top-p (or mirostat).
+	/// Keeps tokens whose surprise is close to the expected surprise of the distribution.
+	pub typical: Option<f32>,
+	#[arg(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+	/// Use mirostat (version 1 or 2) instead of top-p sampling. Mirostat adapts truncation of the distribution to keep the surprise of generated text near `mirostat_tau`.
+	pub mirostat: Option<u8>,
+	#[arg(long, default_value_t = 5.0)]
+	/// Target surprise for mirostat. Lower values yield more focused, less diverse text.
+	pub mirostat_tau: f32,
+	#[arg(long, default_value_t = 0.1)]
+	/// Learning rate of mirostat's running estimate.
+	pub mirostat_eta: f32,
+	#[arg(long, conflicts_with = "mirostat")]
+	/// Decode greedily, except when other tokens have logits within this distance of the top token; then sample among only those tokens.
+	/// Less repetitive than pure greedy decoding, and less random than temperature sampling.
+	pub greedy_with_jitter: Option<f32>,
+	#[arg(long, conflicts_with = "greedy_with_jitter")]
+	/// Sample structural tokens (made only of whitespace and `structural_chars`) among the most likely candidates at this temperature,
+	/// and all other tokens at `temperature`. A lower value keeps formatting crisp while content stays varied.
+	pub structural_temperature: Option<f64>,
+	#[arg(long, requires = "structural_temperature")]
+	/// Characters which, along with whitespace, make up structural tokens. Defaults to ASCII punctuation.
+	pub structural_chars: Option<String>,
+	#[arg(long)]
+	/// Experimental: steer generation away from this prompt, using classifier-free guidance.
+	/// Each step runs a second forward pass over the negative prompt, roughly doubling generation time and KV cache memory.
+	pub negative_prompt: Option<String>,
+	#[arg(long, default_value_t = 1.5, requires = "negative_prompt")]
+	/// Strength of guidance away from the negative prompt. 1.0 disables guidance; larger values steer harder.
+	pub guidance_scale: f64,
 	#[arg(long, default_value_t = 1.1)]
 	pub repeat_penalty: f32,
 	#[arg(long, default_value_t = 64)]
 	pub repeat_last_n: usize,
+	#[arg(long, conflicts_with = "repeat_last_n")]
+	/// Set the repeat penalty window as a fraction of the model's context length (read from the GGUF), instead of `repeat_last_n` tokens.
+	pub repeat_last_frac: Option<f32>,
+	#[arg(long, default_value_t = false)]
+	/// When set, restrict the repeat penalty window to tokens generated for the current prompt, excluding the prompt, system prompt and history.
+	pub repeat_generated_only: bool,
+	#[arg(long)]
+	/// Block any n-gram of this many tokens from occurring twice in the generated text. 0 disables blocking.
+	/// Unlike the repeat penalty, this forbids exact repeats outright, which breaks loops the penalty is too weak to prevent.
+	pub no_repeat_ngram_size: Option<usize>,
+	#[arg(long, default_value_t = 1, conflicts_with_all = ["mirostat", "greedy_with_jitter", "structural_temperature", "negative_prompt"])]
+	/// Decode with beam search over this many candidate sequences, returning the one most likely per token, instead of sampling.
+	/// Output is deterministic; temperature, top-p and the other sampling filters are ignored, but the repeat penalty applies.
+	/// Every beam runs a forward pass per token and keeps a KV cache of its own, so time and memory grow with the number of beams.
+	/// Text is only streamed once the best sequence is known. 1 (the default) samples as usual.
+	pub beams: usize,
 
 	#[arg(short = 'c', long, default_value_t = false)]
-	/// Use CPU when true. Otherwise CUDA/CUDNN.
+	/// Use CPU when true, regardless of --device.
 	pub cpu: bool,
+	#[arg(long, default_value = "auto")]
+	/// Compute device: one of cpu, auto, cuda[:N] or metal[:N]. 'auto' prefers a GPU, falling back to CPU.
+	pub device: DeviceSpec,
+	#[arg(long, default_value_t = false, conflicts_with = "cpu")]
+	/// Fail, rather than fall back to CPU, when no GPU can be used.
+	pub prefer_gpu: bool,
+	#[arg(long, default_value_t = 0)]
+	/// Number of times to retry a forward pass which fails with a (possibly transient) GPU error, such as a failed allocation.
+	pub forward_retries: usize,
+	#[arg(long, default_value_t = false)]
+	/// When set, move logits to CPU before applying penalties and sampling, while the model itself stays on the GPU.
+	/// The penalty and sampling math is a handful of small kernel launches per token, so for small vocabularies it can be faster on CPU
+	/// than paying the launch overhead; for large vocabularies the extra device-to-host copy of the logits may cost more than it saves.
+	/// Has no effect when running on CPU.
+	pub sample_on_cpu: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, run a short throwaway generation after loading the model, so that first-call overhead (notably on CUDA)
+	/// does not count towards the timings reported with `stats`. `bench` always warms up.
+	pub warmup: bool,
 	#[arg(long)]
 	/// Specify path to a file containing potentially partially summarized chat history to be loaded.
-	/// If unspecified, a text file will be opened in local directory with model path information for this chat.
-	/// History file is updated and saved on exit, unless `incognito` is set to true.
+	/// If unspecified, a file named after the model (`<name>.history.jsonl`, from the GGUF's `general.name`, or else the model file's name)
+	/// will be opened in the local directory. A history file named after the model file, if present, is used in preference.
+	/// Every completed turn is appended to the history file immediately, unless `incognito` is set to true.
 	pub historyfile: Option<String>,
 	#[arg(long)]
 	/// Path to file containing text that will comprise the perpetural system prompt that will be provided along with user prompt, rag context and other details.
-	/// If unspecified, a default system prompt will be used.
+	/// May also be an http(s) URL, when built with the `reqwest` feature. If it cannot be read, the default system prompt is used.
+	/// If unspecified, the system prompt embedded in the GGUF by the model's author is used, if it has one, or else a default system prompt.
 	pub sysprompt: Option<String>,
+	#[arg(long, default_value_t = false, requires = "sysprompt")]
+	/// When set, the contents of `sysprompt` are appended to the default system prompt, instead of replacing it.
+	pub system_append: bool,
+	#[arg(long, value_name = "FILE", conflicts_with_all = ["sysprompt", "raw"])]
+	/// Path to a SillyTavern-style character card, as JSON or as a PNG image, whose description, personality, scenario and example dialogue
+	/// make up the system prompt. The character's name becomes `assistant_name` (unless given), and its first message opens new REPL conversations.
+	pub character: Option<String>,
+	#[arg(skip)]
+	/// The character's first message, read from `character` by `fix_options`.
+	pub character_greeting: Option<String>,
+	#[arg(skip)]
+	/// IDs of the options given on the command line (rather than left at their defaults), as recorded by `parse_args`.
+	/// The chat template's recommended sampling parameters only replace options which are not listed.
+	pub explicit: Vec<String>,
+	#[arg(long)]
+	/// Path to a file with additional context (e.g. retrieved documents), which is provided to the LLM along with every prompt.
+	pub context_file: Option<String>,
+	#[arg(long)]
+	/// As `context_file`, but fetched from an http(s) URL (when built with the `reqwest` feature). Fetched text is cached for a few minutes.
+	pub context_url: Option<String>,
+	#[arg(long, requires = "context")]
+	/// Maximum number of tokens of additional context. Longer context is truncated, keeping its beginning.
+	pub context_tokens: Option<usize>,
 	#[arg(long)]
-	/// A string specifying tts option for speech synthesis, in the form "<tts provider>/<internal data>". Ex: "sapi/ZIRA".
+	/// A string specifying tts option for speech synthesis, in the form "<tts provider>/<internal data>". Ex: "sapi/ZIRA", "espeak/en-us",
+	/// or "cmd/<program> <args>" for any program which reads text from stdin. Each completed answer is spoken once it has been generated.
 	/// If unspecified, no speech synthesis will be performed.
 	pub ttsopt: Option<String>,
 	#[arg(short = 'i', long, default_value_t = false)]
@@ -68,26 +174,338 @@ pub struct CliArgs {
 	#[arg(short, long, default_value_t = false)]
 	/// When set, Unless RUST_LOG is also set, default log level is 'trace', log level is 'warn'
 	pub verbose: bool,
+	#[arg(short, long, default_value_t = false)]
+	/// When set, do not show progress indicators, such as while loading the model.
+	pub quiet: bool,
 	#[arg(short = 'B', long)]
-	pub base_model: SupportedBaseModels,
+	/// Architecture of the model. Required by every command except `info`.
+	pub base_model: Option<SupportedBaseModels>,
 	#[arg(short, long)]
-	/// Wehn set, block printing/rendering until LLM emits EOS token.
+	/// When set, print each completion at once when generation ends, instead of streaming it as it is generated.
 	pub no_stream: bool,
 	#[arg(long)]
+	/// Also write generated text to this file, in addition to printing it.
+	pub output_file: Option<String>,
+	#[arg(long, default_value = "token")]
+	/// When streaming, how much text to buffer before printing.
+	pub stream_granularity: StreamGranularity,
+	#[arg(long, default_value = "raw")]
+	/// Format of text printed to stdout.
+	pub stream_format: StreamFormat,
+	#[arg(long, default_value = "", value_parser = parse_escapes)]
+	/// Printed between streamed chunks of raw text, as set by `stream_granularity`; with granularity `word` and "\n", one word per line.
+	/// The escapes \n, \t and \\ are understood.
+	pub chunk_separator: String,
+	#[arg(long, value_name = "MS")]
+	/// Flush streamed text to stdout at most every this many milliseconds (and when a completion ends), rather than after every chunk.
+	/// Saves system calls at high token rates, at the cost of some latency. When unset, every chunk is flushed as it is printed.
+	pub flush_interval: Option<u64>,
+	#[arg(long, default_value_t = false)]
+	/// When set, color the REPL's prompt marker and the assistant's replies, so the conversation is easier to follow.
+	/// Colors are left out when stdout is not a terminal, or when NO_COLOR is set.
+	pub color: bool,
+	#[arg(long)]
+	/// Encoding of text printed to stdout, for terminals which do not display UTF-8. Characters the encoding cannot represent are printed as '?'.
+	/// On Windows, the console output code page is also set to match. If unspecified, UTF-8 is printed, and the code page is left as is.
+	pub output_encoding: Option<OutputEncoding>,
+	#[arg(long, default_value_t = false)]
+	/// When set, normalize whitespace of the system prompt, context and user prompt before applying the template:
+	/// CRLF line endings become LF, trailing whitespace is stripped from lines, and the text is trimmed.
+	pub normalize_prompt: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, accept emphasis markup in user prompts, `(text:weight)` as in `(important:1.3)`, which is stripped to its text before
+	/// the prompt is built. Weights are parsed (and logged at debug level), but not yet applied to generation.
+	pub emphasis: bool,
+	#[arg(long, default_value_t = false, conflicts_with_all = ["validate_template", "prompt_template_file"])]
+	/// When set, feed prompts to the model verbatim, without applying the chat template, for plain text completion with base (non-instruct) models.
+	/// The system prompt, additional context and chat history are all ignored in raw mode.
+	pub raw: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, hide reasoning blocks (delimited by `think_open` and `think_close`) emitted by reasoning models, and only print the final answer.
+	/// The hidden reasoning is logged at debug level.
+	pub strip_thinking: bool,
+	#[arg(long, default_value = "<think>")]
+	/// Tag which opens a reasoning block.
+	pub think_open: String,
+	#[arg(long, default_value = "</think>")]
+	/// Tag which closes a reasoning block.
+	pub think_close: String,
+	#[arg(long)]
+	/// Record the duration of loading, prefill and every decoding step, and write it to this file as a Chrome trace (for chrome://tracing or Perfetto).
+	/// Individual layers are not visible, as the model's forward pass runs as a whole.
+	pub profile: Option<String>,
+	#[arg(long, default_value = "warn")]
+	/// What to do when the prompt encodes to unknown tokens, which usually means the tokenizer does not match the model.
+	pub on_unk: UnkPolicy,
+	#[arg(long, default_value_t = false)]
+	/// When set, stop with an error if the model produces NaN or infinite logits.
+	/// Otherwise a warning is logged, and the most likely finite token is picked for that step.
+	pub strict_finite: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, and the model runs on a GPU, a step whose logits are not all finite is retried once: it is computed again on the same
+	/// device, from the model as it was before the step, and its logits are sampled on CPU. This only helps with transient faults; a kernel
+	/// which always produces non-finite values for a quantization will do so again, so use `--cpu` for those. If the logits are
+	/// still not finite, `strict_finite` applies. Each occurrence is logged as a warning.
+	pub retry_on_nan: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, keep special tokens (such as end-of-turn markers) in the output, instead of skipping them when decoding. Useful for debugging templates.
+	pub keep_special_tokens: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, print the index, ID and decoded piece of every generated token to stderr, as it is produced.
+	pub dump_tokens: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, print every prompt to stderr, with the template applied, before generating from it.
+	pub print_prompt: bool,
+	#[arg(long)]
+	/// Pipe each completion through this command, and use what it prints instead, e.g. for formatting or redaction.
+	/// Output is then not streamed. The command line is split on whitespace, without any shell quoting. If the command fails, the unprocessed completion is used.
+	pub postprocess_cmd: Option<String>,
+	#[arg(long, default_value_t = false)]
+	/// When set, print a sample prompt rendered with the chosen template at startup,
+	/// and warn if the special tokens it uses are missing from the tokenizer or from the chat template embedded in the GGUF.
+	pub validate_template: bool,
+	#[arg(long)]
 	/// Set End-Of-Statement token. If GGUF defines an internal EOS, this is value is overriden.
 	/// If GGUF does not specify any EOS, then it is required to set this.
 	pub eos_token: Option<u32>,
+	#[arg(long, conflicts_with = "eos_token")]
+	/// Set the End-Of-Statement token by its text (such as "<|im_end|>"), which must be a single token of the tokenizer.
+	/// Unlike `eos_token`, this takes precedence over the EOS defined by the GGUF.
+	pub eos_str: Option<String>,
+	#[arg(long)]
+	/// Start every prompt with this token, given by its text (such as "<s>"), if the tokenizer does not already add it.
+	pub bos_str: Option<String>,
+	#[arg(long)]
+	/// Set the unknown token, checked for in prompts (see `on_unk`), by its text, overriding the GGUF and tokenizer.
+	pub unk_str: Option<String>,
+	#[arg(long)]
+	/// Subtract this from the logit of the EOS token for the first `eos_bias_tokens` generated tokens,
+	/// discouraging (without forbidding) models which stop too early.
+	pub eos_bias: Option<f32>,
+	#[arg(long, default_value_t = 16, requires = "eos_bias")]
+	/// Number of generated tokens for which `eos_bias` applies.
+	pub eos_bias_tokens: usize,
 	#[arg(long, default_value_t = 4096)]
 	/// The rough count of how many tokens to retain in history. This value should not be bigger than context size.
 	pub history_count: usize,	// TODO: Infer context size from GGUF and set this to a proportionate value.
+	#[arg(long)]
+	/// Keep only this many of the most recent turns (a user message and its answer) in history, in addition to the `history_count` token limit.
+	/// Whichever limit is reached first drops the oldest messages.
+	pub history_max_turns: Option<usize>,
+	#[arg(long, default_value = "truncate")]
+	/// What to do with a message which alone exceeds `history_count`: truncate it to fit, or keep it whole until the next message pushes it out.
+	pub history_overflow: OverflowPolicy,
 	/// The chat template to apply to user prompt.
+	/// Sampling parameters recommended for the template (see `info templates`) replace the defaults of options not given.
 	#[arg(short = 't', long,  default_value = "chat-ml")]
-	pub template: ChatTemplate
+	pub template: ChatTemplate,
+	#[arg(long)]
+	/// Path to a file with a custom chat template, which overrides `template`.
+	/// The file holds the template for one message, where `{role}` is replaced by the role (system, user, assistant or tool)
+	/// and `{message}` by the message, e.g. "<|{role}|>\n{message}</s>\n".
+	pub prompt_template_file: Option<String>,
+	#[arg(long, value_name = "NAME")]
+	/// Name the assistant in its turns, for roleplay or several bots in one conversation, e.g. `<|im_start|>assistant name=Aria` with ChatML,
+	/// or `Aria:` with i-messenger. Other templates ignore it.
+	pub assistant_name: Option<String>,
+	#[arg(long, default_value = "", value_parser = parse_escapes)]
+	/// Text put before every rendered turn, on top of the chat template, for models needing extra wrapping, or for experiments.
+	/// The escapes \n, \t and \\ are understood.
+	pub turn_prefix: String,
+	#[arg(long, default_value = "", value_parser = parse_escapes)]
+	/// Text put after every rendered turn, as with `turn_prefix`.
+	pub turn_suffix: String,
+	#[arg(long)]
+	/// Maximum number of tokens to generate. If unspecified, generation continues until EOS, a stop sequence, or the model's maximum context length.
+	pub max_tokens: Option<usize>,
+	#[arg(long)]
+	/// Maximum number of tokens (prompt and generation) in a context, instead of the context length declared by the GGUF,
+	/// e.g. for models with wrong metadata, or to limit memory. Prompts which do not fit lose their beginning.
+	pub n_ctx: Option<usize>,
+	#[arg(long)]
+	/// Minimum number of tokens to generate: the EOS token cannot be sampled before this many tokens have been generated.
+	pub min_tokens: Option<usize>,
+	#[arg(long, default_value_t = false, requires = "min_tokens")]
+	/// When set, stop sequences generated before `min_tokens` tokens do not end generation, and are kept in the output.
+	pub min_tokens_ignore_stop: bool,
+	#[arg(long)]
+	/// Stop generating once this many seconds have passed since the invocation started (including prompt processing).
+	/// The output so far is returned, with finish reason `timeout`.
+	pub timeout: Option<f64>,
+	#[arg(long)]
+	/// A sequence of text which ends generation when generated. The stop sequence is not included in the output. May be repeated.
+	pub stop: Vec<String>,
+	#[arg(long, value_name = "ID:COUNT", value_parser = parse_token_count)]
+	/// End generation once the token with this ID has been generated this many times, e.g. the closing brace of a JSON object,
+	/// to extract exactly one object or code block. The token is kept in the output. Finishes with reason `stop`.
+	pub stop_after_token: Option<(u32, usize)>,
+	#[arg(long, default_value_t = false, requires_all = ["stop", "max_tokens"])]
+	/// For `single`: treat stop sequences as breaks between sections, continuing generation past each one until `max_tokens` tokens
+	/// have been generated in all (or generation ends otherwise). Stop sequences are left out of the output, which ends each section.
+	pub continue_after_stop: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, print generation statistics (finish reason, token counts, throughput) to stderr.
+	pub stats: bool,
+	#[arg(long, default_value_t = false)]
+	/// When set, print the time to the first token and percentiles (p50, p90, p99) of the latency between tokens to stderr,
+	/// revealing stalls which the average throughput hides.
+	pub token_timings: bool,
+	#[arg(long, value_name = "TOKENS_PER_SEC")]
+	/// Exit with a nonzero status if decode throughput (generated tokens per second, as reported by `--stats`) falls below this.
+	/// Checked per completion by single and fim, over all prompts by batch, and per model by bench. Pair it with bench, whose
+	/// fixed prompt and token count (and disabled cache) make the measurement comparable between runs, to catch regressions in CI.
+	/// A completion served from the cache has no measured throughput, and fails the check.
+	pub min_tps: Option<f64>,
+	#[arg(long)]
+	/// After generation, save the full token context to this file, so that the session can be resumed later with `load_state`.
+	pub save_state: Option<String>,
+	#[arg(long)]
+	/// Resume from a token context saved with `save_state`. The state must have been saved with the same model.
+	/// Since the KV cache is not saved, the restored context is prefilled again before generation.
+	pub load_state: Option<String>,
+	#[arg(long, value_name = "FILE")]
+	/// Append every generation to this replay log: its input tokens, settings and sampling parameters, and the token chosen at each step.
+	/// The `replay` subcommand runs the log again to check that generation is deterministic. Completions served from the cache are not recorded.
+	pub replay_log: Option<String>,
+	#[arg(long)]
+	/// Cache completions in this directory, and return a cached completion when an invocation is repeated with the same model, prompt and settings.
+	/// Only deterministic invocations are cached: greedy ones, and the first sampled one after loading (which depends only on the seed).
+	pub cache_dir: Option<String>,
+	#[arg(long, default_value_t = false)]
+	/// When set, do not use the completion cache, even if `cache_dir` is given.
+	pub no_cache: bool
 }
 
 impl CliArgs {
-	pub fn fix_options(&mut self) {
-		let mpath = PathBuf::from(self.model_path.clone());
+	/// Parse the command line, as `parse`, recording which options were given explicitly.
+	pub fn parse_args() -> CliArgs {
+		let matches = CliArgs::command().get_matches();
+		let mut args = CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+		args.explicit = matches.ids()
+			.filter(|id| matches.value_source(id.as_str()).is_some_and(|s| s != ValueSource::DefaultValue))
+			.map(|id| id.to_string())
+			.collect();
+		args
+	}
+
+	fn is_explicit(&self, id: &str) -> bool {
+		self.explicit.iter().any(|e| e == id)
+	}
+
+	/// Apply the chat template's recommended sampling parameters to the options not given on the command line.
+	fn apply_template_params(&mut self) {
+		let recommended = self.template.recommended_params();
+		if let Some(t) = recommended.temperature.filter(|_| !self.is_explicit("temperature")) {
+			info!("Using temperature {} recommended for template {:?}", t, self.template);
+			self.temperature = t;
+		}
+		if let Some(p) = recommended.repeat_penalty.filter(|_| !self.is_explicit("repeat_penalty")) {
+			info!("Using repeat penalty {} recommended for template {:?}", p, self.template);
+			self.repeat_penalty = p;
+		}
+		if self.stop.is_empty() && !recommended.stop.is_empty() {
+			info!("Using stop sequences {:?} recommended for template {:?}", recommended.stop, self.template);
+			self.stop = recommended.stop.iter().map(|s| s.to_string()).collect();
+		}
+	}
+
+	/// Check that sampling parameters are within their valid ranges, describing the first one which is not.
+	pub fn validate_sampling(&self) -> Result<(), String> {
+		let positive = |v: f32| v > 0.0;
+		let in_unit = |v: f32| v > 0.0 && v <= 1.0;
+		SamplingParams::from_args(self).validate()?;
+		if !positive(self.repeat_penalty) {
+			return Err(format!("repeat-penalty must be greater than 0 (1 disables it), got {}", self.repeat_penalty));
+		}
+		if let Some(f) = self.repeat_last_frac.filter(|f| !in_unit(*f)) {
+			return Err(format!("repeat-last-frac must be in (0, 1], got {}", f));
+		}
+		if let Some(z) = self.tfs.filter(|z| !in_unit(*z)) {
+			return Err(format!("tfs must be in (0, 1] (1 disables it), got {}", z));
+		}
+		if let Some(p) = self.typical.filter(|p| !in_unit(*p)) {
+			return Err(format!("typical must be in (0, 1] (1 disables it), got {}", p));
+		}
+		if self.mirostat.is_some() && !(positive(self.mirostat_tau) && positive(self.mirostat_eta)) {
+			return Err(format!("mirostat-tau and mirostat-eta must be greater than 0, got {} and {}", self.mirostat_tau, self.mirostat_eta));
+		}
+		if let Some(t) = self.structural_temperature.filter(|t| !(0.0..).contains(t)) {
+			return Err(format!("structural-temperature must be at least 0, got {}", t));
+		}
+		if let Some(eps) = self.greedy_with_jitter.filter(|e| !(0.0..).contains(e)) {
+			return Err(format!("greedy-with-jitter must be at least 0, got {}", eps));
+		}
+		Ok(())
+	}
+
+	/// Requested compute device, where `cpu` takes precedence over `device`.
+	pub fn device_spec(&self) -> DeviceSpec {
+		if self.cpu { DeviceSpec::Cpu } else { self.device }
+	}
+
+	/// How prompts are rendered: the chat template, with the assistant's name and the turn prefix and suffix.
+	pub fn prompt_format(&self) -> PromptFormat {
+		PromptFormat {
+			template: self.template,
+			assistant_name: self.assistant_name.clone(),
+			turn_prefix: self.turn_prefix.clone(),
+			turn_suffix: self.turn_suffix.clone()
+		}
+	}
+
+	/// Fill in defaults which depend on other options, and validate them.
+	/// Errors describe the offending option, and are meant to be reported as usage errors.
+	pub fn fix_options(&mut self) -> Result<(), String> {
+		self.validate_sampling()?;
+
+		if let Some(n) = self.n_ctx.filter(|n| *n < 2) {
+			return Err(format!("n-ctx must be at least 2, got {}", n));
+		}
+
+		if let Some(t) = self.timeout.filter(|t| !t.is_finite() || *t <= 0.0) {
+			return Err(format!("timeout must be a positive number of seconds, got {}", t));
+		}
+
+		if let Some(opt) = &self.ttsopt {
+			tts::from_option(opt)?;
+		}
+
+		if self.postprocess_cmd.as_ref().is_some_and(|c| c.trim().is_empty()) {
+			return Err("postprocess-cmd must not be empty".to_owned());
+		}
+
+		if self.beams == 0 {
+			return Err("beams must be at least 1".to_owned());
+		}
+
+		if self.min_tps.is_some_and(|t| !(t.is_finite() && t > 0.0)) {
+			return Err("min-tps must be a positive number".to_owned());
+		}
+
+		if let Commands::Replay(_) = &self.command {
+			if self.replay_log.is_some() {
+				return Err("--replay-log cannot be used with replay, which would record the log it reads".to_owned());
+			}
+		}
+		if let Commands::Bench(barg) = &self.command {
+			if barg.tokens == 0 {
+				return Err("--tokens must be at least 1 for bench".to_owned());
+			}
+		}
+		if let Commands::Single(parg) = &self.command {
+			if !parg.turns.is_empty() && (self.raw || self.load_state.is_some()) {
+				return Err("--turn cannot be used with --raw or --load-state, which have no conversation to build".to_owned());
+			}
+			parg.validate_turns()?;
+		}
+
+		let Some(model_path) = &self.model_path else {
+			return Err("the argument '--model-path <MODEL_PATH>' is required".to_owned());
+		};
+		if self.base_model.is_none() {
+			return Err("the argument '--base-model <BASE_MODEL>' is required".to_owned());
+		}
+		let mpath = PathBuf::from(model_path);
 
 		// Find tokenizer.json path.
 		if self.tokenizer_json.is_none() {
@@ -98,38 +516,376 @@ impl CliArgs {
 			.map(|e| e.to_owned());
 		}
 
-		// Load system prompt
+		// Load custom chat template.
+		if let Some(path) = &self.prompt_template_file {
+			let text = fs::read_to_string(path).map_err(|e| format!("failed to read prompt template file {}: {}", path, e))?;
+			self.template = ChatTemplate::custom(text).map_err(|e| format!("invalid prompt template file {}: {}", path, e))?;
+		}
+		let card = self.character.as_deref().map(CharacterCard::load).transpose()?;
+		if let Some(card) = &card {
+			self.assistant_name.get_or_insert_with(|| card.name.clone());
+			self.character_greeting = card.greeting();
+		}
+		if let Some(name) = &self.assistant_name {
+			if name.trim().is_empty() || name.contains('\n') {
+				return Err("--assistant-name must be non-empty and on one line".to_owned());
+			}
+			if !self.template.supports_assistant_name() {
+				warn!("The chat template does not support naming the assistant, so --assistant-name is ignored.");
+			}
+		}
+
+		// Raw prompts have no turns to remember.
+		if self.raw {
+			self.disable_history = true;
+		}
+
+		let info = read_gguf_metadata(model_path).ok().map(|(_, _, info)| info);
+		// Default history file, in the working directory, named after the model.
+		if self.historyfile.is_none() {
+			let stem = mpath.file_stem().and_then(|s| s.to_str()).unwrap_or("vocllm");
+			let by_path = format!("{stem}.history.jsonl");
+			// History named after the file is kept, so that it is not lost to the named default.
+			let by_name = info.as_ref()
+				.and_then(|info| info.name.as_deref())
+				.map(|name| name.trim().chars().map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect::<String>())
+				.filter(|name| !name.is_empty() && !Path::new(&by_path).exists())
+				.map(|name| format!("{name}.history.jsonl"));
+			self.historyfile = Some(by_name.unwrap_or(by_path));
+		}
+
+		// Load system prompt: from the file given, the character card, the GGUF, or else the default.
 		self.sysprompt = if let Some(ppath) = &self.sysprompt {
-			match fs::read_to_string(ppath) {
-				Ok(text) => Some(text),
+			match source::read_to_string(ppath) {
+				Ok(text) if self.system_append => {
+					info!("Using the default system prompt, followed by {}", ppath);
+					Some(format!("{}\n{}", DEFUALT_SYSTEM_PROMPT.trim_end(), text))
+				},
+				Ok(text) => {
+					info!("Using system prompt from {}", ppath);
+					Some(text)
+				},
 				Err(e) => {
 					error!("Failed to read system prompt from {}, cause: \"{:?}\" ", ppath, e);
 					Some(DEFUALT_SYSTEM_PROMPT.to_owned())
 				}
 			}
+		} else if let Some(card) = &card {
+			info!("Using system prompt from character card {}", self.character.as_deref().unwrap_or_default());
+			Some(card.system_prompt())
+		} else if let Some(prompt) = info.and_then(|info| info.system_prompt) {
+			info!("Using system prompt embedded in {}", model_path);
+			Some(prompt)
 		} else {
+			info!("Using the default system prompt");
 			Some(DEFUALT_SYSTEM_PROMPT.to_owned())
-		}
+		};
+
+		self.apply_template_params();
+		Ok(())
 	}
 }
 
 #[derive(ValueEnum, Clone, Debug)]
 pub enum SupportedBaseModels {
+	/// Mistral and Mixtral GGUFs, which are run as llama models.
 	Mistral,
+	/// Llama-architecture GGUFs, such as Llama 2 and 3 and most of their derivatives.
 	Llama,
+	/// RWKV models (not yet supported).
 	Rwkv
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum UnkPolicy {
+	/// Log a warning, and generate anyway.
+	Warn,
+	/// Refuse to generate.
+	Error,
+	/// Generate without checking.
+	Ignore
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum StreamGranularity {
+	/// Print text as soon as it is decoded.
+	Token,
+	/// Print text up to the last complete word.
+	Word,
+	/// Print text up to the last complete line.
+	Line
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFormat {
+	/// Generated text as is.
+	Raw,
+	/// Server-sent events: each chunk as a `data: {"text": ...}` event, and `data: [DONE]` once a completion ends.
+	Sse
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputEncoding {
+	Utf8,
+	/// ISO-8859-1.
+	Latin1,
+	Ascii
+}
+
+impl OutputEncoding {
+	/// Encode `text`, replacing characters which cannot be represented with '?'.
+	pub fn encode<'a>(&self, text: &'a str) -> Cow<'a, [u8]> {
+		let limit = match self {
+			Self::Utf8 => return Cow::Borrowed(text.as_bytes()),
+			Self::Latin1 => 0xFF,
+			Self::Ascii => 0x7F
+		};
+		if text.is_ascii() {
+			return Cow::Borrowed(text.as_bytes());
+		}
+		Cow::Owned(text.chars().map(|c| if (c as u32) <= limit { c as u8 } else { b'?' }).collect())
+	}
+
+	/// The Windows code page of this encoding.
+	pub fn code_page(&self) -> u32 {
+		match self {
+			Self::Utf8 => 65001,
+			Self::Latin1 => 28591,
+			Self::Ascii => 20127
+		}
+	}
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
 	/// Enter into a loop, where every iteration stdin is read as user prompt to LLM and inference output is printed/rendered.
 	Ripl,
 	/// Execute exactly one prompt for LLM with the provided system prompt. All chat history related options are overidden and disabled.
-	Single(PromptArg)
+	Single(PromptArg),
+	/// Print metadata of the GGUF file at model_path, without loading the model.
+	Inspect,
+	/// Execute every prompt in a file independently, as with `single`, writing completions to an output file.
+	Batch(BatchArg),
+	/// Print the supported chat templates or base models, with descriptions. Does not need a model.
+	Info(InfoArg),
+	/// Print an embedding of the prompt as JSON, pooled from the model's token embeddings.
+	/// These are not contextual (hidden-state) embeddings: word order is ignored. They suit rough semantic search.
+	Embed(EmbedArg),
+	/// Fill in the middle between a prefix and a suffix (e.g. of code), with a model trained for it such as CodeLlama or Deepseek-Coder.
+	/// The chat template, system prompt and history are not used.
+	Fim(FimArg),
+	/// Shrink the history file, replacing its oldest messages with a summary written by the model, without starting a chat.
+	Compact(CompactArg),
+	/// Report how many tokens a prompt would use with the system prompt, history and context, and whether it fits in the model's context.
+	/// Only the tokenizer and the GGUF header are read; the model is not loaded.
+	Fit(FitArg),
+	/// Time loading and decoding with a fixed prompt for the model at model_path and each `--compare` model, one at a time, and print a comparison table.
+	/// Every model is run with the same settings and tokenizer, so compare quantizations or finetunes of one base model.
+	Bench(BenchArg),
+	/// Run every generation in a replay log (written with `--replay-log`) again, in order, and report the first step at which each one diverges.
+	/// Use the model and settings the log was recorded with. Exits with a nonzero status if any generation diverges.
+	Replay(ReplayArg),
+	/// Print the history file's conversation rendered with the chat template (`-t`), e.g. to carry it over to a model of another family.
+	/// History is recorded without any template, so it is used by any template as it is; `--from` also cleans up markup of the
+	/// template it was recorded under, which models sometimes echo into their answers. The model is not loaded.
+	ConvertHistory(ConvertArg),
+	/// Check that the model and tokenizer load and work together, including a forward pass over one token, printing a checklist.
+	/// Exits with a nonzero status if any check fails.
+	Doctor
 }
 
 #[derive(Debug, Args)]
 pub struct PromptArg {
 	/// The user prompt to be fed to the LLM verbatim.
-	pub prompt: String
+	pub prompt: String,
+	#[arg(long = "turn", value_name = "ROLE:TEXT", value_parser = parse_turn)]
+	/// An earlier message of the conversation, as `user:<text>` or `assistant:<text>`. Repeat in order: turns alternate, starting
+	/// with a user message and ending with an assistant message, which the prompt then follows. History is not used when turns are given.
+	pub turns: Vec<(ChatRole, String)>,
+	#[arg(long, default_value = "human")]
+	/// With json, the completion is not streamed to stdout; instead, one JSON object is printed once generation ends, holding the text,
+	/// the finish reason and the token counts. Other outputs (output file, TTS) are unaffected.
+	pub format: ReportFormat
+}
+
+impl PromptArg {
+	/// Check that turns alternate between user and assistant, so that the prompt follows an assistant message.
+	pub fn validate_turns(&self) -> Result<(), String> {
+		let mut expected = ChatRole::User;
+		for (i, (role, _)) in self.turns.iter().enumerate() {
+			if *role != expected {
+				return Err(format!("turn {} has role {}, expected {} (turns alternate, starting with user)", i + 1, role, expected));
+			}
+			expected = if expected == ChatRole::User { ChatRole::Assistant } else { ChatRole::User };
+		}
+		if expected == ChatRole::Assistant {
+			return Err("the last turn is a user message; the prompt is the next user message, so turns should end with an assistant message".to_owned());
+		}
+		Ok(())
+	}
+}
+
+/// Replace the escapes `\n`, `\t` and `\\` with the characters they stand for. Other backslashes are kept as they are.
+fn parse_escapes(s: &str) -> Result<String, String> {
+	let mut ret = String::with_capacity(s.len());
+	let mut chars = s.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			ret.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('n') => ret.push('\n'),
+			Some('t') => ret.push('\t'),
+			Some('\\') => ret.push('\\'),
+			Some(other) => {
+				ret.push('\\');
+				ret.push(other);
+			},
+			None => ret.push('\\')
+		}
+	}
+	Ok(ret)
+}
+
+fn parse_turn(s: &str) -> Result<(ChatRole, String), String> {
+	let (role, text) = s.split_once(':').ok_or_else(|| format!("turn \"{}\" is not of the form <role>:<text>", s))?;
+	let role = match role.trim() {
+		"user" => ChatRole::User,
+		"assistant" => ChatRole::Assistant,
+		r => return Err(format!("unknown turn role \"{}\", expected user or assistant", r))
+	};
+	Ok((role, text.to_owned()))
+}
+
+/// Parse a `<id>:<count>` pair, as for `--stop-after-token`.
+fn parse_token_count(s: &str) -> Result<(u32, usize), String> {
+	let (id, count) = s.split_once(':').ok_or_else(|| format!("\"{}\" is not of the form <id>:<count>", s))?;
+	let id = id.trim().parse().map_err(|e| format!("invalid token ID \"{}\": {}", id, e))?;
+	match count.trim().parse() {
+		Ok(0) => Err("count must be at least 1".to_owned()),
+		Ok(n) => Ok((id, n)),
+		Err(e) => Err(format!("invalid count \"{}\": {}", count, e))
+	}
+}
+
+#[derive(Debug, Args)]
+pub struct InfoArg {
+	/// What to list.
+	pub topic: InfoTopic
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum InfoTopic {
+	/// Chat templates, for `--template`.
+	Templates,
+	/// Base models, for `--base-model`.
+	Models
+}
+
+#[derive(Debug, Args)]
+pub struct EmbedArg {
+	/// The text to embed.
+	pub prompt: String,
+	#[arg(long, default_value = "mean")]
+	/// How token embeddings are pooled into a single vector.
+	pub pooling: EmbedPooling
+}
+
+#[derive(Debug, Args)]
+pub struct FitArg {
+	/// The user prompt, as it would be given to `single` or in the REPL.
+	pub prompt: String,
+	#[arg(long, default_value_t = false)]
+	/// When set, count the chat history (from `historyfile`), as the REPL would include it.
+	pub with_history: bool,
+	#[arg(long, default_value = "human")]
+	pub format: ReportFormat
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArg {
+	/// Path to the replay log.
+	pub log: String
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct BenchArg {
+	#[arg(long = "compare", value_name = "MODEL_PATH")]
+	/// Another GGUF model to benchmark after the one at model_path. Repeat to compare several.
+	pub models: Vec<String>,
+	#[arg(long, default_value = "Write a short story about a lighthouse keeper.")]
+	/// The user prompt given to every model.
+	pub prompt: String,
+	#[arg(long, default_value_t = 64)]
+	/// Number of tokens each model generates. Overrides max_tokens; EOS and stop sequences may still end generation earlier.
+	pub tokens: usize,
+	#[arg(long, default_value = "human")]
+	pub format: ReportFormat
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+	/// Aligned text, for reading.
+	Human,
+	/// A single JSON object.
+	Json
+}
+
+#[derive(Debug, Args)]
+pub struct CompactArg {
+	#[arg(long)]
+	/// Rough number of tokens of the most recent messages to keep verbatim. Defaults to half of `history_count`.
+	pub keep_tokens: Option<usize>
+}
+
+#[derive(Debug, Args)]
+pub struct ConvertArg {
+	#[arg(long, value_enum)]
+	/// The template the history was recorded under, whose turn markers and special tokens are removed from the messages.
+	pub from: Option<ChatTemplate>,
+	#[arg(short, long)]
+	/// File to write the rendered conversation to, instead of stdout.
+	pub output: Option<PathBuf>,
+	#[arg(long, requires = "from")]
+	/// Also write the cleaned messages back to the history file.
+	pub in_place: bool
+}
+
+#[derive(Debug, Args)]
+pub struct FimArg {
+	#[arg(long, default_value = "")]
+	/// Text before the part to generate.
+	pub prefix: String,
+	#[arg(long, default_value = "")]
+	/// Text after the part to generate.
+	pub suffix: String,
+	#[arg(long)]
+	/// Token which introduces the prefix. If unspecified, it is read from the GGUF.
+	pub fim_pre: Option<String>,
+	#[arg(long)]
+	/// Token which introduces the suffix. If unspecified, it is read from the GGUF.
+	pub fim_suf: Option<String>,
+	#[arg(long)]
+	/// Token after which the middle is generated. If unspecified, it is read from the GGUF.
+	pub fim_mid: Option<String>
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum EmbedPooling {
+	/// Average of all token embeddings.
+	Mean,
+	/// Embedding of the last token.
+	Last
+}
+
+#[derive(Debug, Args)]
+pub struct BatchArg {
+	#[arg(short, long)]
+	/// File with one prompt per line. A line may instead be a JSON object, with the prompt in its "prompt" field,
+	/// and optionally "temperature", "top_p", "max_tokens" and "stop" (a list) overriding the options for that prompt.
+	pub input: String,
+	#[arg(short, long)]
+	/// File to write completions to, one JSON object per line in the same order as the input.
+	pub output: String
 }
\ No newline at end of file
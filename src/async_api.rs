@@ -0,0 +1,63 @@
+//! Module for using the text generator from async code (behind the `tokio` feature).
+//! Generation runs on tokio's blocking thread pool, so it never blocks the async runtime, and text is streamed back through a channel.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+use crate::llmcall::{Completion, GenError, QuantizedTextGenerator};
+
+/// Number of text chunks buffered in a stream before generation waits for the receiver.
+const STREAM_BUFFER: usize = 64;
+
+/// An event of a streamed generation.
+#[derive(Debug)]
+pub enum StreamEvent {
+	/// The next piece of generated text.
+	Text(String),
+	/// Generation has ended. This is always the last event.
+	Done(Result<Completion, GenError>)
+}
+
+/// A generator shared between tasks. Invocations run one at a time, in the order they acquire the generator.
+#[derive(Clone)]
+pub struct AsyncGenerator {
+	inner: Arc<Mutex<QuantizedTextGenerator>>
+}
+
+impl AsyncGenerator {
+	pub fn new(generator: QuantizedTextGenerator) -> Self {
+		Self { inner: Arc::new(Mutex::new(generator)) }
+	}
+
+	/// Invoke the LLM, returning the completion once generation ends.
+	pub async fn invoke(&self, prompt: String) -> Result<Completion, GenError> {
+		let inner = self.inner.clone();
+		tokio::task::spawn_blocking(move || lock(&inner).invoke(&prompt))
+			.await
+			.expect("Generation task panicked")
+	}
+
+	/// Invoke the LLM, receiving generated text as it is produced, and finally the completion.
+	/// Dropping the receiver cancels generation.
+	pub fn stream(&self, prompt: String) -> mpsc::Receiver<StreamEvent> {
+		let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+		let inner = self.inner.clone();
+		tokio::task::spawn_blocking(move || {
+			let cancel = AtomicBool::new(false);
+			let result = lock(&inner).talk_and_map_cancellable(&prompt, &cancel, |text| {
+				if tx.blocking_send(StreamEvent::Text(text.to_owned())).is_err() {
+					cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+				}
+			});
+			let _ = tx.blocking_send(StreamEvent::Done(result));
+		});
+		rx
+	}
+}
+
+/// Lock the generator. A panic during an earlier invocation leaves nothing half-updated that later ones rely on, so poisoning is ignored.
+fn lock(inner: &Mutex<QuantizedTextGenerator>) -> std::sync::MutexGuard<'_, QuantizedTextGenerator> {
+	inner.lock().unwrap_or_else(|e| e.into_inner())
+}
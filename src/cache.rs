@@ -0,0 +1,85 @@
+//! Module for caching completions on disk, so that repeating a deterministic invocation returns its completion without generating again.
+//! Each completion is stored in a JSON file of its own, named after a hash of everything which determines it.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::llmcall::{fnv1a, Completion, FinishReason, FNV_OFFSET_BASIS};
+
+/// A directory of cached completions.
+pub struct CompletionCache {
+	dir: PathBuf
+}
+
+/// A completion as stored in the cache.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+	text: String,
+	finish_reason: FinishReason,
+	prompt_tokens: usize,
+	generated_tokens: usize
+}
+
+impl CompletionCache {
+	/// Use `dir` as the cache, creating it if it does not exist.
+	pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+		let dir = dir.into();
+		fs::create_dir_all(&dir)?;
+		Ok(Self { dir })
+	}
+
+	/// Hash the parts which determine a completion (model, settings, prompt and so on) into its key.
+	/// Each part is preceded by its length, so that moving text from one part to the next changes the key.
+	pub fn key(parts: &[&str]) -> u64 {
+		parts.iter().fold(FNV_OFFSET_BASIS, |h, p| fnv1a(fnv1a(h, &(p.len() as u64).to_le_bytes()), p.as_bytes()))
+	}
+
+	fn path(&self, key: u64) -> PathBuf {
+		self.dir.join(format!("{:016x}.json", key))
+	}
+
+	/// The completion stored under `key`, if any. Entries which cannot be read are treated as missing.
+	pub fn get(&self, key: u64) -> Option<Completion> {
+		let path = self.path(key);
+		let text = fs::read_to_string(&path).ok()?;
+		match serde_json::from_str::<Entry>(&text) {
+			Ok(e) => Some(Completion {
+				text: e.text,
+				finish_reason: e.finish_reason,
+				prompt_tokens: e.prompt_tokens,
+				generated_tokens: e.generated_tokens,
+				elapsed: Duration::ZERO,
+				token_latencies: Vec::new()
+			}),
+			Err(e) => {
+				warn!("Ignoring unreadable cache entry {}, cause: \"{}\"", path.display(), e);
+				None
+			}
+		}
+	}
+
+	/// Store `completion` under `key`. Failure is logged, but not fatal, since the completion has been produced regardless.
+	pub fn put(&self, key: u64, completion: &Completion) {
+		let entry = Entry {
+			text: completion.text.clone(),
+			finish_reason: completion.finish_reason,
+			prompt_tokens: completion.prompt_tokens,
+			generated_tokens: completion.generated_tokens
+		};
+		let path = self.path(key);
+		// The entry is written to a temporary file which then replaces it, so an interrupted write never leaves a partial entry.
+		let tmp = path.with_extension("json.tmp");
+		let result = serde_json::to_vec(&entry)
+			.map_err(io::Error::other)
+			.and_then(|bytes| fs::write(&tmp, bytes))
+			.and_then(|_| fs::rename(&tmp, &path));
+		if let Err(e) = result {
+			warn!("Failed to write cache entry {}, cause: \"{}\"", path.display(), e);
+		}
+	}
+}
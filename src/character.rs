@@ -0,0 +1,145 @@
+//! Module for importing character cards in the SillyTavern format, as JSON files or as PNG images carrying the card in a `tEXt` chunk.
+//! Both the original (v1) cards, with fields at the top level, and v2/v3 cards, with fields under `data`, are read.
+
+use std::fs;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The PNG file signature, which precedes the chunks.
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Keywords of the PNG `tEXt` chunks holding a card, as base64-encoded JSON, in order of preference.
+const PNG_CARD_KEYWORDS: [&str; 2] = ["ccv3", "chara"];
+
+/// Name the user goes by in the card's `{{user}}` placeholders.
+const USER_NAME: &str = "User";
+
+/// The parts of a character card which shape the conversation.
+#[derive(Debug, Deserialize)]
+pub struct CharacterCard {
+	pub name: String,
+	#[serde(default)]
+	pub description: String,
+	#[serde(default)]
+	pub personality: String,
+	#[serde(default)]
+	pub scenario: String,
+	/// The character's first message, with which the conversation opens.
+	#[serde(default)]
+	pub first_mes: String,
+	/// Example dialogue, showing how the character speaks.
+	#[serde(default)]
+	pub mes_example: String
+}
+
+impl CharacterCard {
+	/// Read the card in the JSON or PNG file at `path`, telling the two apart by the PNG signature.
+	pub fn load(path: &str) -> Result<CharacterCard, String> {
+		let bytes = fs::read(path).map_err(|e| format!("failed to read character card {}: {}", path, e))?;
+		let json = if bytes.starts_with(PNG_SIGNATURE) {
+			png_card_text(&bytes)?
+		} else {
+			String::from_utf8(bytes).map_err(|_| "character card is neither a PNG image nor UTF-8 JSON".to_owned())?
+		};
+		let mut value: Value = serde_json::from_str(&json).map_err(|e| format!("invalid character card JSON: {}", e))?;
+		if let Some(data) = value.get_mut("data").filter(|d| d.is_object()) {
+			value = data.take();
+		}
+		let card: CharacterCard = serde_json::from_value(value).map_err(|e| format!("invalid character card: {}", e))?;
+		if card.name.trim().is_empty() {
+			return Err("character card has no name".to_owned());
+		}
+		Ok(card)
+	}
+
+	/// The system prompt describing the character, assembled from the card's description, personality, scenario and example dialogue.
+	pub fn system_prompt(&self) -> String {
+		let mut parts = vec![format!("You are {}. Stay in character, and reply only as {}.", self.name, self.name)];
+		if !self.description.trim().is_empty() {
+			parts.push(self.description.trim().to_owned());
+		}
+		if !self.personality.trim().is_empty() {
+			parts.push(format!("{}'s personality: {}", self.name, self.personality.trim()));
+		}
+		if !self.scenario.trim().is_empty() {
+			parts.push(format!("Scenario: {}", self.scenario.trim()));
+		}
+		if !self.mes_example.trim().is_empty() {
+			parts.push(format!("Example dialogue:\n{}", self.mes_example.replace("<START>", "").trim()));
+		}
+		self.substitute(&parts.join("\n\n"))
+	}
+
+	/// The character's opening message, if the card has one.
+	pub fn greeting(&self) -> Option<String> {
+		let text = self.first_mes.trim();
+		(!text.is_empty()).then(|| self.substitute(text))
+	}
+
+	/// Replace the card's name placeholders, `{{char}}` and `{{user}}` (or the older `<BOT>` and `<USER>`).
+	fn substitute(&self, text: &str) -> String {
+		let mut text = text.to_owned();
+		for (placeholder, name) in [("{{char}}", self.name.as_str()), ("<BOT>", self.name.as_str()), ("{{user}}", USER_NAME), ("<USER>", USER_NAME)] {
+			text = text.replace(placeholder, name);
+		}
+		text
+	}
+}
+
+/// Find the card in the `tEXt` chunks of a PNG image, and decode it from base64.
+fn png_card_text(bytes: &[u8]) -> Result<String, String> {
+	let mut found: Vec<(&str, &[u8])> = Vec::new();
+	let mut rest = &bytes[PNG_SIGNATURE.len()..];
+	// Each chunk is a 4 byte big-endian length, a 4 byte type, the data, and a 4 byte CRC.
+	while rest.len() >= 12 {
+		let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+		let kind = &rest[4..8];
+		let Some(data) = rest.get(8..8 + len) else {
+			return Err("truncated PNG chunk in character card".to_owned());
+		};
+		if kind == b"tEXt" {
+			if let Some(nul) = data.iter().position(|&b| b == 0) {
+				if let Ok(keyword) = std::str::from_utf8(&data[..nul]) {
+					found.push((keyword, &data[nul + 1..]));
+				}
+			}
+		}
+		if kind == b"IEND" {
+			break;
+		}
+		rest = rest.get(12 + len..).unwrap_or_default();
+	}
+	let encoded = PNG_CARD_KEYWORDS.iter()
+		.find_map(|k| found.iter().find(|(keyword, _)| keyword == k))
+		.map(|(_, text)| *text)
+		.ok_or_else(|| "PNG image has no character card (no `chara` or `ccv3` text chunk)".to_owned())?;
+	let decoded = decode_base64(encoded).ok_or_else(|| "character card in PNG image is not valid base64".to_owned())?;
+	String::from_utf8(decoded).map_err(|_| "character card in PNG image is not UTF-8".to_owned())
+}
+
+/// Decode standard base64, with or without padding. Whitespace is skipped.
+fn decode_base64(text: &[u8]) -> Option<Vec<u8>> {
+	let mut out = Vec::with_capacity(text.len() * 3 / 4);
+	let mut acc: u32 = 0;
+	let mut bits = 0;
+	for &c in text.iter().filter(|c| !c.is_ascii_whitespace()) {
+		let v = match c {
+			b'A'..=b'Z' => c - b'A',
+			b'a'..=b'z' => c - b'a' + 26,
+			b'0'..=b'9' => c - b'0' + 52,
+			b'+' => 62,
+			b'/' => 63,
+			b'=' => break,
+			_ => return None
+		};
+		acc = (acc << 6) | v as u32;
+		bits += 6;
+		if bits >= 8 {
+			bits -= 8;
+			out.push((acc >> bits) as u8);
+			acc &= (1 << bits) - 1;
+		}
+	}
+	Some(out)
+}
@@ -1,12 +1,25 @@
-use std::{collections::VecDeque, fmt::{Debug, Display}};
+use std::{borrow::Cow, collections::VecDeque, fmt::{Debug, Display}, fs::{self, OpenOptions}, io::{ErrorKind, Write}, path::Path};
 
 use clap::ValueEnum;
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use crate::tokenizer::{TextTokenizer, TokenizerError};
 
-#[derive(Debug)]
+/// Instructions for summarizing old messages when compacting history.
+const SUMMARY_SYSTEM_PROMPT: &str = "Summarize the following conversation between a user and an assistant in a short paragraph. \
+Keep names, facts, decisions and anything the user asked to be remembered; leave out pleasantries. Reply with only the summary.";
+
+/// Prefix of the system message which replaces compacted messages in history.
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ChatRole {
 	System,
 	User,
-	Assistant
+	Assistant,
+	/// Results of tool (function) calls, fed back to the model.
+	Tool
 }
 
 impl Display for ChatRole {
@@ -14,89 +27,736 @@ impl Display for ChatRole {
         match self {
         	Self::System => write!(f, "system"),
         	Self::User => write!(f, "user"),
-        	Self::Assistant => write!(f, "assistant")
+        	Self::Assistant => write!(f, "assistant"),
+        	Self::Tool => write!(f, "tool")
         }
     }
 }
 
 #[derive(Clone, Copy, Debug, ValueEnum)]
 pub enum ChatTemplate {
+	/// ChatML (`<|im_start|>role ... <|im_end|>`), used by Qwen, OpenHermes, Dolphin and many other fine-tunes.
 	ChatML,
-	IMessenger
+	/// Plain `ROLE: message` lines. Needs no special tokens, so it also suits base models.
+	IMessenger,
+	/// Gemma (`<start_of_turn>role ... <end_of_turn>`). Gemma has no system role, so system messages are folded into the next user message.
+	Gemma,
+	/// A template for a single message, read from a file, with `{role}` and `{message}` placeholders.
+	/// The text is leaked on load (it lives for the whole run anyway), so templates stay `Copy`.
+	#[value(skip)]
+	Custom(&'static str)
 }
 
 impl ChatTemplate {
 
-	pub fn apply_one(&self, role: ChatRole, message: &str) -> String {
+	/// Sampling parameters which suit the template's models, where they differ from the general defaults.
+	pub fn recommended_params(&self) -> RecommendedParams {
 		match self {
-		    Self::ChatML => format!("<|im_start|>{}\n{}<|im_end|>\n", role, message),
-		    Self::IMessenger => format!("{}: {}\n", role.to_string().to_uppercase(), message)
+			// Google's recommended settings for Gemma: sampling at temperature 1, without a repeat penalty.
+			Self::Gemma => RecommendedParams { temperature: Some(1.0), repeat_penalty: Some(1.0), stop: &[] },
+			// Models with no turn tokens to end on tend to go on to write the user's next message.
+			Self::IMessenger => RecommendedParams { stop: &["\nUSER:"], ..Default::default() },
+			Self::ChatML | Self::Custom(_) => RecommendedParams::default()
 		}
 	}
 
-	pub fn generation_lead(&self) -> &str {
+	/// Whether the template renders the assistant's name, when one is set.
+	pub fn supports_assistant_name(&self) -> bool {
+		matches!(self, Self::ChatML | Self::IMessenger)
+	}
+
+	/// Make a custom template from its text, which must contain `{message}` exactly once, and `{role}`.
+	pub fn custom(text: String) -> Result<ChatTemplate, String> {
+		if text.matches("{message}").count() != 1 {
+			return Err("template must contain the {message} placeholder exactly once".to_owned());
+		}
+		if !text.contains("{role}") {
+			return Err("template must contain the {role} placeholder".to_owned());
+		}
+		Ok(Self::Custom(Box::leak(text.into_boxed_str())))
+	}
+
+	/// Special tokens the template relies on, which should be single tokens in the model's vocabulary.
+	pub fn special_tokens(&self) -> &[&str] {
 		match self {
-			Self::ChatML => "<|im_start|>assistant\n",
-			Self::IMessenger => "ASSISTANT: "
+			Self::ChatML => &["<|im_start|>", "<|im_end|>"],
+			Self::Gemma => &["<start_of_turn>", "<end_of_turn>"],
+			Self::IMessenger | Self::Custom(_) => &[]
+		}
+	}
+
+	/// Whether the template formats system messages as such. Otherwise, prompts fold them into the next user message.
+	pub fn has_system_role(&self) -> bool {
+		!matches!(self, Self::Gemma)
+	}
+
+	/// Whether user and assistant turns must alternate, as the model was only trained on such conversations.
+	/// Prompts then merge consecutive messages with the same role into one turn.
+	pub fn requires_alternation(&self) -> bool {
+		matches!(self, Self::Gemma)
+	}
+
+	/// The role of the turn a message of `role` is formatted as, for templates which have fewer roles.
+	fn turn_role(&self, role: ChatRole) -> ChatRole {
+		match (self, role) {
+			(Self::Gemma, ChatRole::Assistant) => ChatRole::Assistant,
+			(Self::Gemma, _) => ChatRole::User,
+			(_, role) => role
+		}
+	}
+}
+
+/// How prompts are rendered: a chat template, with the options applied on top of it.
+/// Unlike the template, the options are not `Copy`, so each chat session (or generator) can have its own.
+#[derive(Clone, Debug)]
+pub struct PromptFormat {
+	pub template: ChatTemplate,
+	/// The assistant's name, which templates that support it render in assistant turns.
+	pub assistant_name: Option<String>,
+	/// Text put before every rendered turn, on top of the template, e.g. for models needing extra wrapping.
+	pub turn_prefix: String,
+	/// Text put after every rendered turn.
+	pub turn_suffix: String
+}
+
+impl From<ChatTemplate> for PromptFormat {
+	fn from(template: ChatTemplate) -> Self {
+		Self { template, assistant_name: None, turn_prefix: String::new(), turn_suffix: String::new() }
+	}
+}
+
+impl PromptFormat {
+
+	pub fn apply_one(&self, role: ChatRole, message: &str) -> String {
+		let turn = match self.template {
+		    ChatTemplate::ChatML => format!("<|im_start|>{}\n{}<|im_end|>\n", self.role_label(role), message),
+		    ChatTemplate::IMessenger => format!("{}: {}\n", self.role_label(role), message),
+		    ChatTemplate::Gemma => {
+				let role = if role == ChatRole::Assistant { "model" } else { "user" };
+				format!("<start_of_turn>{}\n{}<end_of_turn>\n", role, message)
+			},
+		    ChatTemplate::Custom(t) => {
+				// Placeholders in the message itself must not be substituted.
+				let (head, tail) = t.split_once("{message}").unwrap_or((t, ""));
+				let role = role.to_string();
+				format!("{}{}{}", head.replace("{role}", &role), message, tail.replace("{role}", &role))
+			}
+		};
+		if self.turn_prefix.is_empty() && self.turn_suffix.is_empty() {
+			turn
+		} else {
+			format!("{}{}{}", self.turn_prefix, turn, self.turn_suffix)
 		}
 	}
 
+	/// How the template names `role`, including the assistant's name if set and the template supports it.
+	/// Gemma's turn markers and custom templates' `{role}` only take the fixed role names, so they ignore it.
+	fn role_label(&self, role: ChatRole) -> Cow<'_, str> {
+		match (self.template, role, self.assistant_name.as_deref()) {
+			(ChatTemplate::ChatML, ChatRole::Assistant, Some(name)) => Cow::Owned(format!("assistant name={}", name)),
+			(ChatTemplate::IMessenger, ChatRole::Assistant, Some(name)) => Cow::Borrowed(name),
+			(ChatTemplate::IMessenger, _, _) => Cow::Owned(role.to_string().to_uppercase()),
+			_ => Cow::Owned(role.to_string())
+		}
+	}
+
+	/// The start of the assistant's turn, which the model continues. It has the turn prefix, if set, like every other turn.
+	pub fn generation_lead(&self) -> Cow<'_, str> {
+		let lead = match self.template {
+			ChatTemplate::ChatML => Cow::Owned(format!("<|im_start|>{}\n", self.role_label(ChatRole::Assistant))),
+			ChatTemplate::IMessenger => Cow::Owned(format!("{}: ", self.role_label(ChatRole::Assistant))),
+			ChatTemplate::Gemma => Cow::Borrowed("<start_of_turn>model\n"),
+			ChatTemplate::Custom(t) => {
+				let head = t.split_once("{message}").map_or(t, |(h, _)| h);
+				Cow::Owned(head.replace("{role}", &ChatRole::Assistant.to_string()))
+			}
+		};
+		match self.turn_prefix.as_str() {
+			"" => lead,
+			prefix => Cow::Owned(format!("{}{}", prefix, lead))
+		}
+	}
+
+	/// Remove the template's markup from a message recorded under it: a turn header the model repeated at its start,
+	/// an end of turn at its end, and any of the template's special tokens.
+	pub fn strip_markup(&self, message: &str) -> String {
+		let mut text = message.trim();
+		for role in [ChatRole::System, ChatRole::User, ChatRole::Assistant, ChatRole::Tool] {
+			let turn = self.apply_one(role, "\0");
+			let (head, tail) = turn.split_once('\0').unwrap_or((&turn, ""));
+			let (head, tail) = (head.trim(), tail.trim());
+			if !head.is_empty() {
+				text = text.strip_prefix(head).unwrap_or(text).trim_start();
+			}
+			if !tail.is_empty() {
+				text = text.strip_suffix(tail).unwrap_or(text).trim_end();
+			}
+		}
+		let mut text = text.to_owned();
+		for token in self.template.special_tokens() {
+			text = text.replace(token, "");
+		}
+		text.trim().to_owned()
+	}
+
 	pub fn insert_history(&self, buf: &mut String, history: &ChatHistory) {
-		for (_, line) in history.message_queue.iter() {
-			buf.push_str(line);
+		let mut r = Renderer::new(self);
+		for (_, role, message) in history.message_queue.iter() {
+			r.push(*role, message);
 		}
+		buf.push_str(&r.finish());
 	}
 }
 
+/// Sampling parameters recommended for a chat template, which apply instead of the defaults unless given on the command line.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecommendedParams {
+	pub temperature: Option<f64>,
+	pub repeat_penalty: Option<f32>,
+	/// Stop sequences, used when none are given.
+	pub stop: &'static [&'static str]
+}
+
+/// Formats a sequence of messages with a template. For templates without a system role, system messages are held back
+/// and prepended to the next user message, or, when another role follows (or nothing does), given a user turn of their own.
+/// For templates requiring turns to alternate, consecutive messages of the same role are merged into one turn.
+struct Renderer<'a> {
+	format: &'a PromptFormat,
+	buf: String,
+	system: String,
+	/// The turn being built, when merging, which later messages of the same role are appended to.
+	pending: Option<(ChatRole, String)>
+}
+
+impl<'a> Renderer<'a> {
+	fn new(format: &'a PromptFormat) -> Self {
+		Self { format, buf: String::new(), system: String::new(), pending: None }
+	}
+
+	fn push(&mut self, role: ChatRole, message: &str) {
+		if self.format.template.has_system_role() && !self.format.template.requires_alternation() {
+			self.buf.push_str(&self.format.apply_one(role, message));
+			return;
+		}
+		if role == ChatRole::System && !self.format.template.has_system_role() {
+			self.system.push_str(message.trim_end());
+			self.system.push_str("\n\n");
+			return;
+		}
+		let role = self.format.template.turn_role(role);
+		if role == ChatRole::User {
+			let folded = std::mem::take(&mut self.system) + message;
+			self.append(role, folded);
+		} else {
+			self.flush();
+			self.append(role, message.to_owned());
+		}
+	}
+
+	/// Add a turn, merging it into the pending one if the template requires alternation and both have the same role.
+	fn append(&mut self, role: ChatRole, message: String) {
+		if !self.format.template.requires_alternation() {
+			self.buf.push_str(&self.format.apply_one(role, &message));
+			return;
+		}
+		match &mut self.pending {
+			Some((pending_role, text)) if *pending_role == role => {
+				trace!("Merging consecutive {} messages into one turn.", role);
+				text.push_str("\n\n");
+				text.push_str(&message);
+			},
+			_ => {
+				self.emit_pending();
+				self.pending = Some((role, message));
+			}
+		}
+	}
+
+	fn emit_pending(&mut self) {
+		if let Some((role, text)) = self.pending.take() {
+			self.buf.push_str(&self.format.apply_one(role, &text));
+		}
+	}
+
+	/// Give held back system messages a user turn of their own.
+	fn flush(&mut self) {
+		if !self.system.is_empty() {
+			let system = std::mem::take(&mut self.system);
+			self.append(ChatRole::User, system.trim_end().to_owned());
+		}
+	}
+
+	fn finish(mut self) -> String {
+		self.flush();
+		self.emit_pending();
+		self.buf
+	}
+}
+
+/// A message as stored in the history file, which holds one JSON object per line.
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry<'a> {
+	role: ChatRole,
+	content: Cow<'a, str>,
+	/// Set when the content continues the previous message, rather than being a message of its own.
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	continues: bool
+}
+
+/// What chat history does with a message which alone exceeds its token limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OverflowPolicy {
+	/// Keep the beginning of the message, as much as fits in the limit.
+	#[default]
+	Truncate,
+	/// Keep the whole message, over the limit, until the next message pushes it out.
+	Keep
+}
+
+/// Chat messages, without any template applied, so history can be formatted for whichever template is in use.
 pub struct ChatHistory {
 	rough_token_count: usize,
 	token_limit: usize,
-	message_queue: VecDeque<(usize, String)>
+	overflow: OverflowPolicy,
+	/// When set, only this many of the most recent turns (each starting with a user message) are kept, whatever their token count.
+	turn_limit: Option<usize>,
+	message_queue: VecDeque<(usize, ChatRole, String)>
 }
 
 impl ChatHistory {
 
 	pub fn new(limit: usize) -> ChatHistory {
-		ChatHistory { rough_token_count: 0, token_limit: limit, message_queue: VecDeque::new() }
+		ChatHistory { rough_token_count: 0, token_limit: limit, overflow: OverflowPolicy::default(), turn_limit: None, message_queue: VecDeque::new() }
+	}
+
+	/// Handle messages which alone exceed the token limit according to `policy`, instead of truncating them.
+	pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> ChatHistory {
+		self.overflow = policy;
+		self
+	}
+
+	/// Also limit history to the `max_turns` most recent turns, dropping older ones right away.
+	/// Whichever of the token and turn limits is reached first drops messages.
+	pub fn with_max_turns(mut self, max_turns: Option<usize>) -> ChatHistory {
+		self.turn_limit = max_turns;
+		self.enforce_turn_limit();
+		self
+	}
+
+	/// Load history from a file written by `append_turn_to_file`. A missing file yields empty history.
+	/// Lines which cannot be parsed (such as a partial line left by a crash mid-write) are skipped with a warning.
+	pub fn load_from(path: &Path, limit: usize) -> std::io::Result<ChatHistory> {
+		ChatHistory::new(limit).read_from(path)
+	}
+
+	/// As `load_from`, adding the messages to this history, so that its settings (such as the overflow policy) apply to them.
+	pub fn read_from(self, path: &Path) -> std::io::Result<ChatHistory> {
+		let mut ret = self;
+		let text = match fs::read_to_string(path) {
+			Ok(t) => t,
+			Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ret),
+			Err(e) => return Err(e)
+		};
+		for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+			match serde_json::from_str::<HistoryEntry>(line) {
+				Ok(entry) if entry.continues => ret.extend_last_message(&entry.content),
+				Ok(entry) => ret.record_message(entry.role, &entry.content),
+				Err(e) => warn!("Skipping malformed line {} of history file {}, cause: \"{}\"", i + 1, path.display(), e)
+			}
+		}
+		debug!("Loaded {} messages from history file {}", ret.message_queue.len(), path.display());
+		Ok(ret)
+	}
+
+	/// Append the messages of a completed turn to the history file, creating it if necessary.
+	/// The turn is written with a single write, so that a crash cannot leave only part of it behind.
+	pub fn append_turn_to_file(path: &Path, messages: &[(ChatRole, &str)]) -> std::io::Result<()> {
+		let entries: Vec<HistoryEntry> = messages.iter()
+			.map(|(role, content)| HistoryEntry { role: *role, content: Cow::Borrowed(content), continues: false })
+			.collect();
+		Self::append_entries(path, &entries)
+	}
+
+	/// Append text continuing the last assistant message to the history file.
+	pub fn append_continuation_to_file(path: &Path, text: &str) -> std::io::Result<()> {
+		Self::append_entries(path, &[HistoryEntry { role: ChatRole::Assistant, content: Cow::Borrowed(text), continues: true }])
+	}
+
+	fn append_entries(path: &Path, entries: &[HistoryEntry]) -> std::io::Result<()> {
+		let mut buf = String::new();
+		for entry in entries {
+			buf.push_str(&serde_json::to_string(entry).map_err(std::io::Error::from)?);
+			buf.push('\n');
+		}
+		let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+		file.write_all(buf.as_bytes())?;
+		file.sync_data()
+	}
+
+	/// Write the whole history to `path`, replacing its contents.
+	/// The history is first written to a temporary file alongside, which is then renamed over `path`, so a crash cannot lose it.
+	pub fn save_to(&self, path: &Path) -> std::io::Result<()> {
+		let entries: Vec<HistoryEntry> = self.message_queue.iter()
+			.map(|(_, role, content)| HistoryEntry { role: *role, content: Cow::Borrowed(content), continues: false })
+			.collect();
+		let mut tmp = path.as_os_str().to_owned();
+		tmp.push(".tmp");
+		let tmp = Path::new(&tmp);
+		if tmp.exists() {
+			fs::remove_file(tmp)?;
+		}
+		Self::append_entries(tmp, &entries)?;
+		fs::rename(tmp, path)
+	}
+
+	/// Remove the oldest messages until the rough token count is at most `keep_tokens`, returning them oldest first.
+	/// Answers following removed messages are removed too, so that no turn is split.
+	pub fn drain_oldest(&mut self, keep_tokens: usize) -> Vec<(ChatRole, String)> {
+		let mut ret = Vec::new();
+		let splits_turn = |q: &VecDeque<(usize, ChatRole, String)>, removed: &[(ChatRole, String)]| {
+			!removed.is_empty() && q.front().is_some_and(|(_, role, _)| *role == ChatRole::Assistant)
+		};
+		while self.rough_token_count > keep_tokens || splits_turn(&self.message_queue, &ret) {
+			let Some((n, role, message)) = self.message_queue.pop_front() else {
+				break;
+			};
+			self.rough_token_count -= n;
+			ret.push((role, message));
+		}
+		ret
+	}
+
+	/// Add a message before all others, such as a summary of dropped messages. The token limit is not enforced.
+	pub fn prepend_message(&mut self, role: ChatRole, message: &str) {
+		let n_tokens = (message.split_whitespace().count() * 4) / 3;
+		self.message_queue.push_front((n_tokens, role, message.to_owned()));
+		self.rough_token_count += n_tokens;
+	}
+
+	pub fn len(&self) -> usize {
+		self.message_queue.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.message_queue.is_empty()
+	}
+
+	/// The most recent message, if any.
+	pub fn last_message(&self) -> Option<(ChatRole, &str)> {
+		self.message_queue.back().map(|(_, role, message)| (*role, message.as_str()))
+	}
+
+	/// Append text to the most recent message, e.g. when the model continues its last answer.
+	pub fn extend_last_message(&mut self, text: &str) {
+		if let Some((n, _, message)) = self.message_queue.back_mut() {
+			message.push_str(text);
+			let n_tokens = (message.split_whitespace().count() * 4) / 3;
+			self.rough_token_count = self.rough_token_count - *n + n_tokens;
+			*n = n_tokens;
+		}
 	}
 
-	pub fn record_message(&mut self, message: &str) {
-		let n_new_tokens = (message.split_whitespace().count() * 4) / 3;
-		self.message_queue.push_back((n_new_tokens, message.to_owned()));
+	/// Remove `format`'s markup from every message, as left by a model which echoed it, returning how many messages changed.
+	/// Messages left empty are dropped.
+	pub fn strip_markup(&mut self, format: &PromptFormat) -> usize {
+		let mut changed = 0;
+		for (n, _, message) in self.message_queue.iter_mut() {
+			let stripped = format.strip_markup(message);
+			if stripped != *message {
+				changed += 1;
+				*message = stripped;
+				let n_tokens = (message.split_whitespace().count() * 4) / 3;
+				self.rough_token_count = self.rough_token_count - *n + n_tokens;
+				*n = n_tokens;
+			}
+		}
+		self.message_queue.retain(|(_, _, message)| !message.is_empty());
+		changed
+	}
+
+	/// The number of tokens the messages in history encode to, without any template applied.
+	pub fn token_count(&self, tokenizer: &dyn TextTokenizer) -> Result<usize, TokenizerError> {
+		self.message_queue.iter()
+			.map(|(_, _, message)| tokenizer.encode(message.as_str(), false).map(|ids| ids.len()))
+			.sum()
+	}
+
+	/// The number of tokens beyond which old messages are dropped.
+	pub fn token_limit(&self) -> usize {
+		self.token_limit
+	}
+
+	pub fn record_message(&mut self, role: ChatRole, message: &str) {
+		let mut message = Cow::Borrowed(message);
+		let mut n_new_tokens = (message.split_whitespace().count() * 4) / 3;
+		if n_new_tokens > self.token_limit {
+			match self.overflow {
+				OverflowPolicy::Truncate => {
+					// Rough counts are 4 tokens per 3 words, so keep as many words as make up the limit.
+					let keep_words = self.token_limit * 3 / 4;
+					let end = message.split_whitespace().nth(keep_words)
+						.map_or(message.len(), |w| w.as_ptr() as usize - message.as_ptr() as usize);
+					warn!("A {} message of about {} tokens exceeds the history limit of {}, keeping only its first {} words.", role, n_new_tokens, self.token_limit, keep_words);
+					message = Cow::Owned(message[..end].trim_end().to_owned());
+					n_new_tokens = (message.split_whitespace().count() * 4) / 3;
+				},
+				OverflowPolicy::Keep => warn!("A {} message of about {} tokens exceeds the history limit of {}, keeping it over the limit.", role, n_new_tokens, self.token_limit)
+			}
+		}
+		self.message_queue.push_back((n_new_tokens, role, message.into_owned()));
 		self.rough_token_count += n_new_tokens;
 
 		// Current strategy is to just discard old chats.
 		// TODO: Add chat history summarization.
-		while self.rough_token_count > self.token_limit {
-			if let Some((n, _)) = self.message_queue.pop_front() {
+		// The newest message is never discarded, since it only exceeds the limit when kept so deliberately.
+		while self.rough_token_count > self.token_limit && self.message_queue.len() > 1 {
+			if let Some((n, _, _)) = self.message_queue.pop_front() {
 				self.rough_token_count -= n;
-			} else {
-				panic!("Cannot remove anything from history to reduce token count! This should not happen.");
 			}
 		}
+		self.enforce_turn_limit();
 	}
+
+	/// Drop the oldest turns, along with anything before them (such as a summary), until at most `turn_limit` remain.
+	fn enforce_turn_limit(&mut self) {
+		let Some(max_turns) = self.turn_limit else {
+			return;
+		};
+		let count_turns = |q: &VecDeque<(usize, ChatRole, String)>| q.iter().filter(|(_, role, _)| *role == ChatRole::User).count();
+		while count_turns(&self.message_queue) > max_turns {
+			// Drop messages up to and including the first user message, then the rest of its turn.
+			let mut in_turn = false;
+			while let Some((_, role, _)) = self.message_queue.front() {
+				if *role == ChatRole::User && in_turn {
+					break;
+				}
+				in_turn |= *role == ChatRole::User;
+				let (n, _, _) = self.message_queue.pop_front().expect("Front message exists");
+				self.rough_token_count -= n;
+			}
+		}
+	}
+}
+
+/// Normalize whitespace in text: convert CRLF line endings to LF, strip trailing whitespace from each line, and trim the text.
+/// Prompts read from files on different platforms then tokenize the same.
+pub fn normalize_text(text: &str) -> String {
+	text.replace("\r\n", "\n")
+		.lines()
+		.map(|l| l.trim_end())
+		.collect::<Vec<_>>()
+		.join("\n")
+		.trim()
+		.to_owned()
 }
 
-pub fn make_prompt_with_history(template: ChatTemplate, system_prompt: &str, user_prompt: &str, mut additional_context: Option<String>, history: &mut ChatHistory) -> String {
-	let mut ret = String::new();
-	ret.push_str(&template.apply_one(ChatRole::System, system_prompt));
-	template.insert_history(&mut ret, history);
+/// Remove emphasis markup, `(text:weight)` with a numeric weight, from a prompt, keeping the emphasized text.
+/// Returns the plain text, and each emphasized span with its weight, innermost first for nested markup.
+/// Weights are not yet applied to generation; stripping keeps the markup from confusing the model.
+pub fn strip_emphasis(text: &str) -> (String, Vec<(String, f32)>) {
+	let mut text = text.to_owned();
+	let mut spans = Vec::new();
+	// Each pass removes the innermost markup, so nested markup takes as many passes as it is deep.
+	loop {
+		let mut out = String::with_capacity(text.len());
+		let mut rest = text.as_str();
+		let mut changed = false;
+		while let Some(open) = rest.find('(') {
+			let after = &rest[open + 1..];
+			let parsed = after.find(['(', ')']).filter(|&end| after.as_bytes()[end] == b')').and_then(|end| {
+				let (span, weight) = after[..end].rsplit_once(':')?;
+				let weight: f32 = weight.trim().parse().ok()?;
+				(!span.trim().is_empty() && weight.is_finite()).then_some((span, weight, end))
+			});
+			match parsed {
+				Some((span, weight, end)) => {
+					out.push_str(&rest[..open]);
+					out.push_str(span);
+					spans.push((span.to_owned(), weight));
+					rest = &after[end + 1..];
+					changed = true;
+				},
+				None => {
+					out.push_str(&rest[..=open]);
+					rest = after;
+				}
+			}
+		}
+		out.push_str(rest);
+		text = out;
+		if !changed {
+			return (text, spans);
+		}
+	}
+}
+
+fn prepare(text: &str, normalize: bool) -> Cow<'_, str> {
+	if normalize {
+		let ret = normalize_text(text);
+		trace!("Normalized prompt text: {:?}", ret);
+		Cow::Owned(ret)
+	} else {
+		Cow::Borrowed(text)
+	}
+}
+
+/// Make a prompt in which the model continues its last answer: the history up to the last (assistant) message,
+/// then the assistant lead and that message, left open with no end of turn.
+pub fn make_continue_prompt(format: &PromptFormat, system_prompt: &str, mut additional_context: Option<String>, history: &ChatHistory, normalize: bool) -> String {
+	let mut r = Renderer::new(format);
+	r.push(ChatRole::System, &prepare(system_prompt, normalize));
+	let n = history.message_queue.len().saturating_sub(1);
+	for (_, role, message) in history.message_queue.iter().take(n) {
+		r.push(*role, message);
+	}
 	if let Some(actx) = additional_context.take() {
-		let formatted_context = template.apply_one(ChatRole::System, &actx);
-		ret.push_str(&formatted_context);
+		r.push(ChatRole::System, &prepare(&actx, normalize));
+	}
+	let mut ret = r.finish();
+	ret.push_str(&format.generation_lead());
+	if let Some((_, last)) = history.last_message() {
+		ret.push_str(last);
 	}
-	let user_prompt = template.apply_one(ChatRole::User, user_prompt);
-	history.record_message(&user_prompt);
-	ret.push_str(template.generation_lead());
 	ret
 }
 
-pub fn make_prompt(template: ChatTemplate, system_prompt: &str, user_prompt: &str, mut additional_context: Option<String>) -> String {
-	let mut ret = String::new();
-	ret.push_str(&template.apply_one(ChatRole::System, system_prompt));
-	if let Some(text) = additional_context.take() {
-		ret.push_str(&template.apply_one(ChatRole::System, &text));
+/// Truncate `text` to at most `max_tokens` tokens, keeping its beginning, so that large additional context fits in the prompt.
+pub fn truncate_to_tokens<'a>(text: &'a str, tokenizer: &dyn TextTokenizer, max_tokens: usize) -> Result<Cow<'a, str>, TokenizerError> {
+	let ids = tokenizer.encode(text, false)?;
+	let n_tokens = ids.len();
+	if n_tokens <= max_tokens {
+		return Ok(Cow::Borrowed(text));
+	}
+	let kept = tokenizer.decode(&ids[..max_tokens], false)?;
+	warn!("Truncated additional context from {} to {} tokens, dropping {} of {} bytes.", n_tokens, max_tokens, text.len().saturating_sub(kept.len()), text.len());
+	Ok(Cow::Owned(kept))
+}
+
+/// Make a prompt containing the system prompt, then the chat history, and then the user prompt. The caller records the turn in history once it completes.
+pub fn make_prompt_with_history(format: &PromptFormat, system_prompt: &str, user_prompt: &str, mut additional_context: Option<String>, history: &ChatHistory, normalize: bool) -> String {
+	let mut r = Renderer::new(format);
+	r.push(ChatRole::System, &prepare(system_prompt, normalize));
+	for (_, role, message) in history.message_queue.iter() {
+		r.push(*role, message);
 	}
-	ret.push_str(&template.apply_one(ChatRole::User, user_prompt));
+	if let Some(actx) = additional_context.take() {
+		r.push(ChatRole::System, &prepare(&actx, normalize));
+	}
+	r.push(ChatRole::User, &prepare(user_prompt, normalize));
+	let mut ret = r.finish();
+	ret.push_str(&format.generation_lead());
 	ret
+}
+
+/// Suppresses reasoning blocks (e.g. `<think>...</think>`) emitted by reasoning models, passing through only the final answer.
+/// Text is fed incrementally, so tags split across chunks are handled. Hidden reasoning is logged at debug level.
+pub struct ThinkingFilter {
+	open: String,
+	close: String,
+	thinking: bool,
+	/// Set after a reasoning block ends, to drop the whitespace separating it from the answer.
+	trim_next: bool,
+	buf: String,
+	reasoning: String
+}
+
+impl ThinkingFilter {
+	pub fn new(open: &str, close: &str) -> Self {
+		ThinkingFilter {
+			open: open.to_owned(),
+			close: close.to_owned(),
+			thinking: false,
+			trim_next: false,
+			buf: String::new(),
+			reasoning: String::new()
+		}
+	}
+
+	/// Feed a chunk of generated text, and return the part of it which is visible.
+	pub fn push(&mut self, text: &str) -> String {
+		self.buf.push_str(text);
+		let mut out = String::new();
+		loop {
+			let tag = if self.thinking { &self.close } else { &self.open };
+			match self.buf.find(tag.as_str()) {
+				Some(pos) => {
+					let before = self.buf[..pos].to_owned();
+					self.buf.drain(..pos + tag.len());
+					self.emit(&before, &mut out);
+					if self.thinking {
+						debug!("Model reasoning: {}", self.reasoning.trim());
+						self.reasoning.clear();
+						self.trim_next = true;
+					}
+					self.thinking = !self.thinking;
+				},
+				None => {
+					// Hold back the longest suffix which could still become the tag.
+					let keep = (1..tag.len()).rev()
+						.find(|k| tag.is_char_boundary(*k) && self.buf.ends_with(&tag[..*k]))
+						.unwrap_or(0);
+					let n = self.buf.len() - keep;
+					let before: String = self.buf.drain(..n).collect();
+					self.emit(&before, &mut out);
+					return out;
+				}
+			}
+		}
+	}
+
+	/// Flush any held back text, at the end of generation.
+	pub fn finish(&mut self) -> String {
+		let rest = std::mem::take(&mut self.buf);
+		let mut out = String::new();
+		self.emit(&rest, &mut out);
+		if self.thinking && !self.reasoning.is_empty() {
+			debug!("Model reasoning (unterminated): {}", self.reasoning.trim());
+			self.reasoning.clear();
+		}
+		out
+	}
+
+	fn emit(&mut self, text: &str, out: &mut String) {
+		if self.thinking {
+			self.reasoning.push_str(text);
+			return;
+		}
+		let text = if self.trim_next { text.trim_start() } else { text };
+		if !text.is_empty() {
+			self.trim_next = false;
+			out.push_str(text);
+		}
+	}
+}
+
+/// Make a prompt asking the model to summarize `messages`, so that they can be replaced by the summary in history.
+/// Earlier summaries (system messages) are included, so repeated compaction keeps what they recorded.
+pub fn make_summary_prompt(format: &PromptFormat, messages: &[(ChatRole, String)]) -> String {
+	let transcript: Vec<String> = messages.iter().map(|(role, message)| match role {
+		ChatRole::System => message.clone(),
+		_ => format!("{}: {}", role.to_string().to_uppercase(), message)
+	}).collect();
+	let mut ret = make_prompt(format, SUMMARY_SYSTEM_PROMPT, &transcript.join("\n"), None, false);
+	ret.push_str(&format.generation_lead());
+	ret
+}
+
+/// Make a prompt that continues an existing context, so the system prompt is not repeated.
+pub fn make_followup_prompt(format: &PromptFormat, user_prompt: &str, mut additional_context: Option<String>, normalize: bool) -> String {
+	let mut r = Renderer::new(format);
+	if let Some(text) = additional_context.take() {
+		r.push(ChatRole::System, &prepare(&text, normalize));
+	}
+	r.push(ChatRole::User, &prepare(user_prompt, normalize));
+	r.finish()
+}
+
+pub fn make_prompt(format: &PromptFormat, system_prompt: &str, user_prompt: &str, mut additional_context: Option<String>, normalize: bool) -> String {
+	let mut r = Renderer::new(format);
+	r.push(ChatRole::System, &prepare(system_prompt, normalize));
+	if let Some(text) = additional_context.take() {
+		r.push(ChatRole::System, &prepare(&text, normalize));
+	}
+	r.push(ChatRole::User, &prepare(user_prompt, normalize));
+	r.finish()
 }
\ No newline at end of file
@@ -0,0 +1,15 @@
+//! Library interface of vocllm, for embedding the text generator (or just its tokenizer and chat templates) in other programs.
+
+pub mod argsc;
+#[cfg(feature = "tokio")]
+pub mod async_api;
+pub mod cache;
+pub mod character;
+pub mod chat;
+pub mod llmcall;
+pub mod profile;
+pub mod sampling;
+pub mod source;
+pub mod stream;
+pub mod tokenizer;
+pub mod tts;
@@ -1,54 +1,613 @@
 //! Module to load and make inference calls on LLMs.
 
-use candle_core::quantized::gguf_file::Content;
-use std::fs::File;
-use std::time::Instant;
+use candle_core::quantized::gguf_file::{Content, Value};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
+use std::time::{Duration, Instant};
 
 use log::{debug, info, trace};
 use log::{error, warn};
 use candle_transformers::generation::LogitsProcessor;
 use candle_core::{Device, Tensor};
-use candle_transformers::models::quantized_llama::ModelWeights;
-use tokenizers::Tokenizer;
+use candle_transformers::models::quantized_llama::{ModelWeights, MAX_SEQ_LEN};
+use tokenizers::models::bpe::BPE;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::{AddedToken, Tokenizer};
+use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::argsc::CliArgs;
+use crate::argsc::{CliArgs, EmbedPooling, UnkPolicy};
+use crate::cache::CompletionCache;
+use crate::chat::{make_prompt, ChatTemplate, PromptFormat};
+use crate::profile::{self, Profiler};
+use crate::sampling::{self, GreedyJitter, Mirostat, NgramBlocker, RngAlgo, StructuralTemperature, TopPSampler};
+use crate::tokenizer::TextTokenizer;
 
 const DEBUG_TOKEN_COUNT: usize = 128;
 
+/// Times a token hook may ask for a resample at one step, after which the last sampled token is kept.
+const MAX_TOKEN_RESAMPLES: usize = 16;
+
+/// Prompt and number of decoding steps of a warmup pass.
+const WARMUP_PROMPT: &str = "Hello";
+const WARMUP_STEPS: usize = 4;
+
+/// Delay before the first retry of a failed forward pass, doubled for every further retry.
+const FORWARD_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Metadata keys under which GGUFs may embed a default system prompt, in order of preference. None of them is standard.
+const SYSTEM_PROMPT_KEYS: [&str; 2] = ["general.system_prompt", "tokenizer.chat_system_prompt"];
+
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
 pub struct QuantizedTextGenerator {
 	model: ModelWeights,
 	device: Device,
-	tokenizer: Tokenizer,
-	logits_processor: LogitsProcessor,
+	tokenizer: Box<dyn TextTokenizer + Send>,
+    /// Temperature/top-p sampler for the default parameters, used unless mirostat or jittered greedy decoding is.
+    sampler: BaseSampler,
+    /// Default sampling parameters, for invocations which do not pass their own.
+    params: SamplingParams,
+    seed: u64,
+    rng: RngAlgo,
+    tfs: Option<f32>,
+    typical: Option<f32>,
+    /// When set, mirostat replaces top-p/temperature sampling.
+    mirostat: Option<Mirostat>,
+    /// When set, jittered greedy decoding replaces top-p/temperature sampling.
+    greedy_jitter: Option<GreedyJitter>,
+    /// When set, structural tokens are sampled at a temperature of their own.
+    structural: Option<StructuralTemperature>,
+    /// Formatted negative prompt, which guidance steers generation away from.
+    negative_prompt: Option<String>,
+    guidance_scale: f64,
     repeat_penalty: f32,
     repeat_last_n: usize,
-    eos: u32
-}
-
-fn get_device(cpu: bool) -> Device{
-	if cpu {
-		Device::Cpu
-	} else if candle_core::utils::cuda_is_available() {
-		match Device::new_cuda(0) {
-			Ok(a) => a,
-			Err(e) => {
-				error!("Failed to acquire cuda device, cause: \"{:?}\", falling back to CPU", e);
-				Device::Cpu
-			}
-		}
-	} else {
-		warn!("CUDA is not available. Falling back to CPU");
-		Device::Cpu
-	}
+    /// When set, the repeat penalty only considers tokens generated in the current invocation.
+    repeat_generated_only: bool,
+    /// Records spans of loading and generation, when profiling.
+    profiler: Option<Profiler>,
+    /// Number of times a forward pass is retried after a transient device error.
+    forward_retries: usize,
+    /// When set, every generated token is printed to stderr.
+    dump_tokens: bool,
+    /// When set, special tokens are left out of decoded text.
+    skip_special: bool,
+    /// When set, stop sequences do not end generation before `min_tokens` tokens.
+    min_tokens_ignore_stop: bool,
+    /// When set, non-finite logits are an error, instead of falling back to argmax over the finite ones.
+    strict_finite: bool,
+    /// Subtracted from the EOS logit for the first `eos_bias_tokens` generated tokens.
+    eos_bias: Option<f32>,
+    eos_bias_tokens: usize,
+    /// When set, no n-gram of this size may occur twice in the generated text.
+    no_repeat_ngram_size: Option<usize>,
+    /// Number of sequences kept by beam search. With 1, tokens are sampled instead.
+    beams: usize,
+    /// When set, generation stops once this token has been generated this many times.
+    stop_after_token: Option<(u32, usize)>,
+    /// When set, logits are moved to CPU before penalties and sampling.
+    sample_on_cpu: bool,
+    /// When set, a step with non-finite logits on the GPU is computed again, and sampled on CPU.
+    retry_on_nan: bool,
+    /// Maximum number of tokens (prompt and generation) in a context.
+    n_ctx: usize,
+    special: SpecialTokens,
+    /// When set, every context starts with this token, whether or not the tokenizer adds it.
+    bos: Option<u32>,
+    on_unk: UnkPolicy,
+    /// Wall-clock limit on a single invocation.
+    timeout: Option<Duration>,
+    model_hash: u64,
+    /// When set, deterministic invocations are looked up in, and stored to, this cache.
+    cache: Option<CompletionCache>,
+    /// Everything besides the prompt and sampling parameters which determines generated text, as part of completion cache keys.
+    cache_settings: String,
+    /// The model's name (`general.name`) and architecture (`general.architecture`), as recorded in the GGUF.
+    name: Option<String>,
+    architecture: Option<String>,
+    /// When set, called with every sampled token, and may replace it or have it resampled.
+    token_hook: Option<TokenHook>,
+    /// When set, every generation is appended to this replay log.
+    replay_log: Option<String>,
+    /// Set once an invocation has drawn from the sampler's RNG (or updated mirostat's state), after which sampled text also depends on earlier invocations.
+    sampled: bool,
+    /// Full token context (prompt and generation) of the most recent invocation.
+    context: Vec<u32>,
+    /// Token context restored from a saved state, to be continued by the next invocation.
+    resume_from: Option<Vec<u32>>
 }
 
-fn load_model_infallible(path: &str, device: &Device) -> (ModelWeights, Option<u32>) {
-    trace!("Loading model {}", path);
-    let load_start = Instant::now();
-    let mut file = File::open(path).expect("Failed to open model file.");
-    let model = Content::read(&mut file).map_err(|e| e.with_path(path)).expect("Failed to read GGUF file content");
-    trace!("Checking metadata for EOS information...");
-    let eos_token_id = model.metadata.get("tokenizer.ggml.eos_token_id").and_then(|v| v.to_u32().ok());
+/// IDs of the special tokens, resolved once when the generator is created.
+#[derive(Debug, Clone)]
+pub struct SpecialTokens {
+    /// The token contexts start with: forced with `--bos-str`, or else as given by the GGUF.
+    pub bos: Option<u32>,
+    pub eos: u32,
+    pub unk: Option<u32>,
+    pub pad: Option<u32>,
+    /// The fill-in-the-middle prefix, suffix and middle tokens, for models trained for it.
+    pub fim: [Option<u32>; 3],
+    /// The chat template's special tokens (such as `<|im_start|>`), with their IDs, or `None` where the vocabulary lacks them.
+    pub template: Vec<(String, Option<u32>)>
+}
+
+impl SpecialTokens {
+    /// The ID of the chat template's special token `text`, or a description of why there is none.
+    pub fn template_token(&self, text: &str) -> Result<u32, String> {
+        match self.template.iter().find(|(t, _)| t == text) {
+            Some((_, Some(id))) => Ok(*id),
+            Some((_, None)) => Err(format!("special token {:?} of the chat template is not in the tokenizer vocabulary", text)),
+            None => Err(format!("{:?} is not a special token of the chat template", text))
+        }
+    }
+
+    /// Whether `id` is one of these special tokens.
+    pub fn contains(&self, id: u32) -> bool {
+        [self.bos, Some(self.eos), self.unk, self.pad].contains(&Some(id)) || self.template.iter().any(|(_, t)| *t == Some(id))
+    }
+}
+
+/// What a token hook decides about a sampled token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenDecision {
+    Accept,
+    /// Use this token instead.
+    Replace(u32),
+    /// Sample again, with the rejected token excluded.
+    Resample
+}
+
+/// A hook called with every sampled token and the logits it was sampled from, as set with `QuantizedTextGenerator::set_token_hook`.
+pub type TokenHook = Box<dyn FnMut(u32, &Tensor) -> TokenDecision + Send>;
+
+/// Sampling parameters which may be chosen per invocation, such as per line of a batch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    /// Maximum number of tokens to generate.
+    pub max_tokens: Option<usize>,
+    /// EOS cannot be generated before this many tokens.
+    pub min_tokens: Option<usize>,
+    /// Sequences of text which end generation. They are not included in the output.
+    pub stop: Vec<String>
+}
+
+impl SamplingParams {
+    pub fn from_args(args: &CliArgs) -> Self {
+        Self { temperature: args.temperature, top_p: args.top_p, max_tokens: args.max_tokens, min_tokens: args.min_tokens, stop: args.stop.clone() }
+    }
+
+    /// Check that the parameters are within range, describing the first which is not.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..).contains(&self.temperature) {
+            return Err(format!("temperature must be at least 0 (0 samples greedily), got {}", self.temperature));
+        }
+        if let Some(p) = self.top_p.filter(|p| !(*p > 0.0 && *p <= 1.0)) {
+            return Err(format!("top-p must be in (0, 1], got {}; omit it to disable nucleus sampling", p));
+        }
+        Ok(())
+    }
+}
+
+/// Sampling by temperature and top-p, with candle's `LogitsProcessor` or a portable sampler of our own.
+enum BaseSampler {
+    // Boxed, as its random number generator's buffer makes it much larger than the portable sampler.
+    Candle(Box<LogitsProcessor>),
+    Portable(TopPSampler),
+    /// At temperature 0, the argmax, with ties broken by token ID. Candle's argmax leaves ties to the device, which may differ between platforms.
+    Greedy
+}
+
+impl BaseSampler {
+    fn new(rng: RngAlgo, seed: u64, temperature: f64, top_p: Option<f64>) -> Self {
+        match rng {
+            _ if temperature <= 1e-7 => Self::Greedy,
+            RngAlgo::Candle => Self::Candle(Box::new(LogitsProcessor::new(seed, Some(temperature), top_p))),
+            _ => Self::Portable(TopPSampler::new(temperature, top_p, sampling::seeded_rng(rng, seed)))
+        }
+    }
+
+    fn sample(&mut self, logits: &Tensor) -> Result<u32, GenError> {
+        Ok(match self {
+            Self::Candle(p) => p.sample(logits)?,
+            Self::Portable(s) => s.sample(&logits.to_vec1::<f32>()?),
+            Self::Greedy => sampling::argmax(&logits.to_vec1::<f32>()?)
+        })
+    }
+}
+
+/// Errors that may occur while invoking the LLM.
+#[derive(Debug)]
+pub enum GenError {
+    /// The prompt is empty, or contains only whitespace.
+    EmptyPrompt,
+    /// A file could not be opened, read or written.
+    Io(std::io::Error),
+    /// The tokenizer failed to encode or decode.
+    Tokenizer(tokenizers::Error),
+    /// A tensor operation (forward pass, sampling, etc.) failed.
+    Model(candle_core::Error),
+    /// The requested compute device is unavailable.
+    Device(String),
+    /// The prompt contains this many tokens which the tokenizer could not represent, and the policy is to reject it.
+    UnknownTokens(usize),
+    /// The logits for the token at this position (of the generated tokens) contain NaN or infinite values.
+    NonFiniteLogits(usize),
+    /// Sampling parameters passed for an invocation are out of range.
+    InvalidParams(String)
+}
+
+/// How a caller may handle a `GenError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GenErrorKind {
+    /// The same request may succeed when retried, possibly on CPU (e.g. a GPU error, or non-finite logits).
+    Recoverable,
+    /// The request or configuration is wrong, and must be fixed by the user (e.g. an empty prompt, or a mismatched tokenizer).
+    UserFixable,
+    /// Retrying will not help, such as when the model file is corrupt.
+    Fatal
+}
+
+impl Display for GenErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Recoverable => write!(f, "recoverable"),
+            Self::UserFixable => write!(f, "user-fixable"),
+            Self::Fatal => write!(f, "fatal")
+        }
+    }
+}
+
+impl GenError {
+    /// Whether the error may be retried, must be fixed by the user, or is fatal.
+    pub fn kind(&self) -> GenErrorKind {
+        match self {
+            Self::EmptyPrompt | Self::Tokenizer(_) | Self::UnknownTokens(_) | Self::InvalidParams(_) => GenErrorKind::UserFixable,
+            Self::Io(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied) => GenErrorKind::UserFixable,
+            Self::Io(_) => GenErrorKind::Fatal,
+            Self::Model(e) if is_transient(e) => GenErrorKind::Recoverable,
+            Self::Model(_) => GenErrorKind::Fatal,
+            Self::Device(_) | Self::NonFiniteLogits(_) => GenErrorKind::Recoverable
+        }
+    }
+}
+
+impl Display for GenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyPrompt => write!(f, "prompt is empty"),
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Tokenizer(e) => write!(f, "tokenizer error: {} (check that the tokenizer belongs to the model; `doctor` compares them)", e),
+            Self::Model(e) if is_transient(e) => write!(f, "model error: {} (the device failed; retry, or run on CPU with --cpu)", e),
+            Self::Model(e) => write!(f, "model error: {}", e),
+            Self::Device(e) => write!(f, "device error: {} (run on CPU with --cpu)", e),
+            Self::UnknownTokens(n) => write!(f, "prompt contains {} unknown tokens (the tokenizer may not match the model; `--on-unk warn` generates anyway)", n),
+            Self::NonFiniteLogits(i) => write!(f, "logits for generated token {} are not finite (try --retry-on-nan, or --cpu)", i),
+            Self::InvalidParams(e) => write!(f, "invalid sampling parameters: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for GenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Tokenizer(e) => Some(e.as_ref()),
+            Self::Model(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<candle_core::Error> for GenError {
+    fn from(e: candle_core::Error) -> Self {
+        Self::Model(e)
+    }
+}
+
+/// The reason generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The model emitted EOS, i.e, the answer is complete.
+    Eos,
+    /// The token limit, or the model's maximum context length was reached. The answer may be truncated.
+    Length,
+    /// A stop sequence was generated.
+    Stop,
+    /// Generation was cancelled (e.g. by Ctrl-C). The answer is truncated.
+    Cancelled,
+    /// The time limit was reached. The answer may be truncated.
+    Timeout
+}
+
+impl Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Eos => write!(f, "eos"),
+            Self::Length => write!(f, "length"),
+            Self::Stop => write!(f, "stop"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Timeout => write!(f, "timeout")
+        }
+    }
+}
+
+/// Generated output of an invocation, along with some statistics.
+#[derive(Debug)]
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    /// Time spent in token generation, excluding tokenization.
+    pub elapsed: Duration,
+    /// Time taken by each decoding step, from the forward pass until the token is chosen: the first includes the prompt,
+    /// and the rest are the latencies between tokens. Empty for cached completions.
+    pub token_latencies: Vec<Duration>
+}
+
+impl Completion {
+    /// Decode throughput: generated tokens per second of `elapsed`. Zero when nothing was timed, as for cached completions.
+    pub fn tokens_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.generated_tokens as f64 / secs } else { 0.0 }
+    }
+}
+
+/// A snapshot of the token context after a generation, which can be written to disk and resumed later.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GenerationState {
+    /// Fingerprint of the model metadata this state was generated with.
+    pub model_hash: u64,
+    pub tokens: Vec<u32>
+}
+
+/// One generation as recorded in a replay log, which holds one JSON object per line: its inputs and the token chosen at every step.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    /// Fingerprint of the model metadata the generation ran with.
+    pub model_hash: u64,
+    /// Every other setting which determines the generated tokens, as in completion cache keys.
+    pub settings: String,
+    pub params: SamplingParams,
+    /// The full token context the generation started from, including any resumed context.
+    pub input: Vec<u32>,
+    /// The token chosen at each step.
+    pub output: Vec<u32>,
+    pub finish_reason: FinishReason,
+    /// Set when no earlier generation had drawn from the sampler's random number generator, as after loading the model.
+    /// Replay reseeds the generator for such entries, so that a log may span several runs.
+    pub fresh_rng: bool
+}
+
+/// Compute device on which to run the model, as requested on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+    /// Use the first CUDA or Metal device if one is available, and CPU otherwise.
+    Auto
+}
+
+impl std::str::FromStr for DeviceSpec {
+    type Err = String;
+
+    /// Parse `cpu`, `auto`, `cuda`, `metal`, or a GPU backend with an ordinal such as `cuda:1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (backend, ordinal) = match s.split_once(':') {
+            Some((b, n)) => (b, Some(n.parse::<usize>().map_err(|_| format!("invalid device ordinal '{n}'"))?)),
+            None => (s, None)
+        };
+        match (backend.to_lowercase().as_str(), ordinal) {
+            ("cpu", None) => Ok(Self::Cpu),
+            ("auto", None) => Ok(Self::Auto),
+            ("cuda", n) => Ok(Self::Cuda(n.unwrap_or(0))),
+            ("metal", n) => Ok(Self::Metal(n.unwrap_or(0))),
+            ("cpu" | "auto", Some(_)) => Err(format!("device '{backend}' does not take an ordinal")),
+            _ => Err(format!("unknown device '{s}', expected one of cpu, auto, cuda[:N], metal[:N]"))
+        }
+    }
+}
+
+impl DeviceSpec {
+    /// Acquire the requested device. Explicitly requested GPU backends are never silently replaced by CPU,
+    /// and neither is `Auto` when `require_gpu` is set.
+    pub fn resolve(self, require_gpu: bool) -> Result<Device, GenError> {
+        match self {
+            Self::Cpu if require_gpu => Err(GenError::Device("CPU was requested, but a GPU is required".to_owned())),
+            Self::Cpu => {
+                if candle_core::utils::cuda_is_available() || candle_core::utils::metal_is_available() {
+                    info!("Using CPU as requested, although GPU support is available.");
+                }
+                Ok(Device::Cpu)
+            },
+            Self::Cuda(n) => {
+                if !candle_core::utils::cuda_is_available() {
+                    return Err(GenError::Device("CUDA support was not compiled in".to_owned()));
+                }
+                Device::new_cuda(n).map_err(|e| GenError::Device(format!("failed to acquire CUDA device {n}: {e}")))
+            },
+            Self::Metal(n) => {
+                if !candle_core::utils::metal_is_available() {
+                    return Err(GenError::Device("Metal support was not compiled in".to_owned()));
+                }
+                Device::new_metal(n).map_err(|e| GenError::Device(format!("failed to acquire Metal device {n}: {e}")))
+            },
+            Self::Auto => {
+                let gpu = if candle_core::utils::cuda_is_available() {
+                    Some(Self::Cuda(0))
+                } else if candle_core::utils::metal_is_available() {
+                    Some(Self::Metal(0))
+                } else {
+                    None
+                };
+                match gpu.map(|g| g.resolve(require_gpu)) {
+                    Some(Ok(d)) => Ok(d),
+                    Some(Err(e)) if require_gpu => Err(e),
+                    Some(Err(e)) => {
+                        error!("{}, falling back to CPU", e);
+                        Ok(Device::Cpu)
+                    },
+                    None if require_gpu => Err(GenError::Device("a GPU is required, but neither CUDA nor Metal support was compiled in".to_owned())),
+                    None => {
+                        warn!("Neither CUDA nor Metal is available. Falling back to CPU");
+                        Ok(Device::Cpu)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fold `bytes` into an FNV-1a `hash`. Unlike `DefaultHasher`, its output is fixed, so it can name things stored on disk.
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, b| (h ^ (*b as u64)).wrapping_mul(FNV_PRIME))
+}
+
+fn hash_value(hash: u64, value: &Value) -> u64 {
+    match value {
+        Value::Array(items) => items.iter().fold(fnv1a(hash, &(items.len() as u64).to_le_bytes()), hash_value),
+        Value::String(s) => fnv1a(hash, s.as_bytes()),
+        v => fnv1a(hash, format!("{:?}", v).as_bytes())
+    }
+}
+
+/// Compute a stable fingerprint of GGUF metadata and tensor layout, used to check that saved states belong to the loaded model.
+fn model_fingerprint(content: &Content) -> u64 {
+    let mut keys: Vec<&String> = content.metadata.keys().collect();
+    keys.sort();
+    let mut hash = keys.into_iter().fold(FNV_OFFSET_BASIS, |h, k| {
+        hash_value(fnv1a(h, k.as_bytes()), &content.metadata[k])
+    });
+    let mut names: Vec<&String> = content.tensor_infos.keys().collect();
+    names.sort();
+    for name in names {
+        let info = &content.tensor_infos[name];
+        hash = fnv1a(hash, name.as_bytes());
+        hash = fnv1a(hash, format!("{:?}{:?}", info.ggml_dtype, info.shape.dims()).as_bytes());
+    }
+    hash
+}
+
+/// Information about a model, read from the header of its GGUF file.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    pub architecture: Option<String>,
+    /// The model's name (`general.name`), as given by whoever converted it.
+    pub name: Option<String>,
+    pub context_length: Option<usize>,
+    pub eos_token_id: Option<u32>,
+    pub bos_token_id: Option<u32>,
+    pub unk_token_id: Option<u32>,
+    pub pad_token_id: Option<u32>,
+    pub n_tensors: usize,
+    pub total_size_in_bytes: usize,
+    /// Jinja chat template embedded in the GGUF, if any.
+    pub chat_template: Option<String>,
+    /// Default system prompt embedded in the GGUF by the model's author, if any.
+    pub system_prompt: Option<String>,
+    /// IDs of the fill-in-the-middle prefix, suffix and middle tokens, for models trained for it.
+    pub fim_token_ids: [Option<u32>; 3],
+    /// Fingerprint of the metadata and tensor layout.
+    pub model_hash: u64
+}
+
+impl ModelInfo {
+    /// Number of tokens (prompt and generation) an invocation can use: `n_ctx` if given, or else the model's context length,
+    /// capped at the longest sequence candle's llama implementation supports.
+    pub fn usable_context(&self, n_ctx: Option<usize>) -> usize {
+        n_ctx.or(self.context_length).unwrap_or(MAX_SEQ_LEN).min(MAX_SEQ_LEN)
+    }
+}
+
+/// Whether a model was tuned to follow instructions in chat turns, or is a base model, which only continues text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelKind {
+    Instruct,
+    Base,
+    /// The metadata gives no hint either way.
+    Unknown
+}
+
+impl Display for ModelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Instruct => write!(f, "instruct"),
+            Self::Base => write!(f, "base"),
+            Self::Unknown => write!(f, "unknown")
+        }
+    }
+}
+
+/// Words in model names which mark instruct (or chat) fine-tunes, matched against the lowercased name split at non-alphanumeric characters.
+const INSTRUCT_NAME_MARKERS: [&str; 14] = [
+    "instruct", "chat", "it", "sft", "dpo", "rlhf", "assistant", "hermes", "dolphin", "openchat", "zephyr", "vicuna", "alpaca", "orca"
+];
+
+impl ModelInfo {
+    /// The model's name, or the name of its file at `path` if the GGUF does not name it, for messages.
+    pub fn display_name<'a>(&'a self, path: &'a str) -> &'a str {
+        self.name.as_deref().unwrap_or_else(|| Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path))
+    }
+
+    /// Guess whether the model is instruct-tuned or a base model, from its name and whether it embeds a chat template.
+    /// The name is the stronger signal, as some base models ship with their instruct sibling's template. A name which only repeats
+    /// the architecture (as some converters write) says nothing, and without a name or template the kind is unknown.
+    pub fn kind(&self) -> ModelKind {
+        let name = self.name.as_deref()
+            .map(str::to_lowercase)
+            .filter(|n| self.architecture.as_deref().is_none_or(|a| n.trim() != a.to_lowercase()));
+        if let Some(name) = &name {
+            let words: Vec<&str> = name.split(|c: char| !c.is_alphanumeric()).collect();
+            if words.iter().any(|w| INSTRUCT_NAME_MARKERS.contains(w)) {
+                return ModelKind::Instruct;
+            }
+            if words.contains(&"base") {
+                return ModelKind::Base;
+            }
+        }
+        match (&self.chat_template, &name) {
+            (Some(_), _) => ModelKind::Instruct,
+            (None, Some(_)) => ModelKind::Base,
+            (None, None) => ModelKind::Unknown
+        }
+    }
+}
+
+/// Find the ID of the token whose text is `text`: a token of the vocabulary (or an added token), or text which encodes to a single token.
+pub fn token_id_of(tokenizer: &dyn TextTokenizer, text: &str) -> Result<u32, String> {
+    if let Some(id) = tokenizer.token_to_id(text) {
+        return Ok(id);
+    }
+    match tokenizer.encode(text, false).map_err(|e| e.to_string())?.as_slice() {
+        [id] => Ok(*id),
+        ids => Err(format!("not a single token of the tokenizer (it encodes to {} tokens)", ids.len()))
+    }
+}
+
+/// Read the header (metadata and tensor information) of a GGUF file, without loading any weights.
+/// The returned file can be used to subsequently load weights.
+pub fn read_gguf_metadata(path: &str) -> Result<(File, Content, ModelInfo), GenError> {
+    let mut file = File::open(path).map_err(GenError::Io)?;
+    let model = Content::read(&mut file).map_err(|e| e.with_path(path))?;
+    trace!("Checking metadata...");
+    let md_u32 = |key: &str| model.metadata.get(key).and_then(|v| v.to_u32().ok());
+    let architecture = model.metadata.get("general.architecture").and_then(|v| v.to_string().ok()).cloned();
+    let context_length = architecture.as_ref()
+        .and_then(|a| md_u32(&format!("{a}.context_length")))
+        .map(|n| n as usize);
+    let eos_token_id = md_u32("tokenizer.ggml.eos_token_id");
+    let bos_token_id = md_u32("tokenizer.ggml.bos_token_id");
+    let model_hash = model_fingerprint(&model);
     let mut total_size_in_bytes = 0;
     trace!("Inspecting tensors...");
     for (_, tensor) in model.tensor_infos.iter() {
@@ -56,23 +615,295 @@ fn load_model_infallible(path: &str, device: &Device) -> (ModelWeights, Option<u
         total_size_in_bytes +=
             elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
     }
+    let info = ModelInfo {
+        name: model.metadata.get("general.name").and_then(|v| v.to_string().ok()).cloned(),
+        architecture,
+        context_length,
+        eos_token_id,
+        bos_token_id,
+        unk_token_id: md_u32("tokenizer.ggml.unknown_token_id"),
+        pad_token_id: md_u32("tokenizer.ggml.padding_token_id"),
+        n_tensors: model.tensor_infos.len(),
+        total_size_in_bytes,
+        chat_template: model.metadata.get("tokenizer.chat_template").and_then(|v| v.to_string().ok()).cloned(),
+        system_prompt: SYSTEM_PROMPT_KEYS.iter()
+            .find_map(|k| model.metadata.get(*k).and_then(|v| v.to_string().ok()).filter(|p| !p.trim().is_empty()))
+            .cloned(),
+        // Older GGUFs name these prefix/suffix/middle, newer ones fim_pre/fim_suf/fim_mid.
+        fim_token_ids: [("prefix", "fim_pre"), ("suffix", "fim_suf"), ("middle", "fim_mid")].map(|(old, new)| {
+            md_u32(&format!("tokenizer.ggml.{old}_token_id")).or_else(|| md_u32(&format!("tokenizer.ggml.{new}_token_id")))
+        }),
+        model_hash
+    };
+    Ok((file, model, info))
+}
+
+/// Load the model at `path` and run a single forward pass over `token`, returning the logits. Used to check a setup works.
+pub fn forward_one(path: &str, device: &Device, token: u32) -> Result<Vec<f32>, GenError> {
+    let (mut file, content, _) = read_gguf_metadata(path)?;
+    let mut model = ModelWeights::from_gguf(content, &mut file, device)?;
+    let input = Tensor::new(&[token], device)?.unsqueeze(0)?;
+    let logits = model.forward(&input, 0)?.squeeze(0)?;
+    Ok(logits.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?)
+}
+
+/// Compute an embedding of `text` by pooling the input token embeddings (`token_embd.weight`) of the model at `path`.
+/// The quantized llama forward pass only exposes logits, not hidden states, so these embeddings are not contextual.
+/// Only the embedding table is loaded, but it is dequantized in full, which takes `vocab size * embedding length * 4` bytes.
+pub fn embed_text(path: &str, tokenizer: &dyn TextTokenizer, text: &str, pooling: EmbedPooling, device: &Device) -> Result<Vec<f32>, GenError> {
+    let ids = tokenizer.encode(text, false).map_err(GenError::Tokenizer)?;
+    if ids.is_empty() {
+        return Err(GenError::EmptyPrompt);
+    }
+    let (mut file, content, _) = read_gguf_metadata(path)?;
+    trace!("Loading token embeddings...");
+    let table = content.tensor(&mut file, "token_embd.weight", device)?.dequantize(device)?;
+    let embeddings = table.index_select(&Tensor::new(ids.as_slice(), device)?, 0)?;
+    let pooled = match pooling {
+        EmbedPooling::Mean => embeddings.mean(0)?,
+        EmbedPooling::Last => embeddings.get(ids.len() - 1)?
+    };
+    Ok(pooled.to_dtype(candle_core::DType::F32)?.to_vec1()?)
+}
+
+/// Whether an error comes from the GPU backend, and so may be transient (e.g. an allocation failure on a contended device).
+fn is_transient(e: &candle_core::Error) -> bool {
+    match e {
+        candle_core::Error::WithBacktrace { inner, .. } | candle_core::Error::WithPath { inner, .. } => is_transient(inner),
+        candle_core::Error::Cuda(_) | candle_core::Error::Metal(_) => true,
+        _ => false
+    }
+}
+
+/// Run a forward pass, retrying up to `retries` times after transient errors, with exponential backoff.
+/// A failed pass may already have extended the KV cache of some layers, so the model is restored before every retry.
+fn forward_with_retries(model: &mut ModelWeights, input: &Tensor, seqoff: usize, retries: usize) -> candle_core::Result<Tensor> {
+    if retries == 0 {
+        return model.forward(input, seqoff);
+    }
+    // Cheap: weights are shared, only the KV cache tensor handles are copied.
+    let snapshot = model.clone();
+    let mut attempt = 0;
+    loop {
+        match model.forward(input, seqoff) {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = FORWARD_RETRY_DELAY * 2u32.pow(attempt as u32);
+                attempt += 1;
+                warn!("Forward pass failed, cause: \"{}\", retrying in {}ms ({}/{})", e, delay.as_millis(), attempt, retries);
+                std::thread::sleep(delay);
+                *model = snapshot.clone();
+            },
+            r => return r
+        }
+    }
+}
+
+/// Log rope parameters, and warn if the model asks for rope scaling.
+/// candle's quantized llama only reads `rope.freq_base`; scaling (linear, yarn, etc.) is not applied, and cannot be from outside the model.
+fn check_rope_scaling(model: &Content, arch: &str) {
+    let prefix = format!("{arch}.rope.");
+    let mut rope: Vec<(&str, String)> = model.metadata.iter()
+        .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|k| (k, format!("{:?}", v))))
+        .collect();
+    rope.sort();
+    debug!("Rope parameters: {:?}", rope);
+
+    let md = |key: &str| model.metadata.get(&format!("{prefix}{key}"));
+    let scaling_type = md("scaling.type").and_then(|v| v.to_string().ok()).map(|s| s.as_str()).filter(|t| *t != "none");
+    let factor = md("scaling.factor").or_else(|| md("scale_linear"))
+        .and_then(|v| v.to_f32().ok())
+        .filter(|f| *f != 0.0 && *f != 1.0);
+    if scaling_type.is_some() || factor.is_some() {
+        let original = md("scaling.original_context_length").and_then(|v| v.to_u32().ok());
+        warn!("Model specifies rope scaling (type: {}, factor: {}), which is not applied; output quality will degrade beyond {}.",
+            scaling_type.unwrap_or("linear"),
+            factor.map(|f| f.to_string()).unwrap_or_else(|| "unspecified".to_owned()),
+            original.map(|n| format!("the original context length of {n} tokens")).unwrap_or_else(|| "the original context length".to_owned())
+        );
+    }
+}
+
+/// Position of the earliest stop sequence in `text` which ends after byte `floor`.
+fn find_stop(text: &str, stop: &[String], floor: usize) -> Option<usize> {
+    stop.iter()
+        .filter_map(|s| text.match_indices(s.as_str()).map(|(pos, _)| pos).find(|pos| pos + s.len() > floor))
+        .min()
+}
+
+/// Lower-precision backends occasionally produce NaN or infinite logits, which would poison penalties and sampling (or panic in them).
+/// Whether every logit is finite, checked with a single reduction wherever the logits are.
+fn all_finite(logits: &Tensor) -> Result<bool, GenError> {
+    Ok(logits.sum_all()?.to_scalar::<f32>()?.is_finite())
+}
+
+/// Returns the token to use instead of sampling at step `step`: the argmax of the finite logits, if any are not finite.
+/// Only a sum is read back from the device when all logits are finite.
+fn finite_fallback(logits: &Tensor, strict: bool, step: usize) -> Result<Option<u32>, GenError> {
+    if all_finite(logits)? {
+        return Ok(None);
+    }
+    if strict {
+        return Err(GenError::NonFiniteLogits(step));
+    }
+    let values = logits.to_vec1::<f32>()?;
+    let best = values.iter().enumerate()
+        .filter(|(_, l)| l.is_finite())
+        .fold(None, |best: Option<(usize, f32)>, (i, l)| if best.is_some_and(|(_, b)| b >= *l) { best } else { Some((i, *l)) });
+    match best {
+        Some((i, _)) => {
+            warn!("Logits for generated token {} are not all finite, picking the most likely finite token {} instead of sampling.", step, i);
+            Ok(Some(i as u32))
+        },
+        None => Err(GenError::NonFiniteLogits(step))
+    }
+}
+
+/// Load model weights from the GGUF file at `path`.
+/// When `progress` is set and stderr is a terminal, a progress bar over the bytes read is shown while loading.
+fn load_model_infallible(path: &str, device: &Device, progress: bool, mut profiler: Option<&mut Profiler>) -> (ModelWeights, ModelInfo) {
+    trace!("Loading model {}", path);
+    let load_start = Instant::now();
+    let (mut file, model, info) = read_gguf_metadata(path).unwrap_or_else(|e| {
+        error!("Failed to read GGUF file {}", path);
+        panic!("{e:?}");
+    });
+    if let Some(p) = profiler.as_mut() {
+        p.record("read gguf header", "load", load_start, None);
+    }
+    let weights_start = Instant::now();
+    check_rope_scaling(&model, info.architecture.as_deref().unwrap_or("llama"));
 
-    let n_tensors = model.tensor_infos.len();
     trace!("Loading model weights...");
-    let ret = ModelWeights::from_gguf(model, &mut file, device).expect("Failed to load model from GGUF file.");
+    let ret = if progress && std::io::stderr().is_terminal() {
+        let file_len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        let bar = ProgressBar::new(file_len).with_style(
+            ProgressStyle::with_template("{spinner} Loading model [{bar:40}] {bytes}/{total_bytes} ({eta})")
+                .expect("Invalid progress bar template")
+                .progress_chars("=> ")
+        );
+        let mut reader = bar.wrap_read(file);
+        let ret = ModelWeights::from_gguf(model, &mut reader, device);
+        bar.finish_and_clear();
+        ret
+    } else {
+        ModelWeights::from_gguf(model, &mut file, device)
+    }.expect("Failed to load model from GGUF file.");
+    if let Some(p) = profiler.as_mut() {
+        p.record("load weights", "load", weights_start, Some(serde_json::json!({ "tensors": info.n_tensors, "bytes": info.total_size_in_bytes })));
+    }
+
+    info!("Successfully loaded {} ({}) from {} [{} tensors, {} bytes] in {:.2}s",
+        info.display_name(path), info.architecture.as_deref().unwrap_or("unknown architecture"), path,
+        info.n_tensors, info.total_size_in_bytes, load_start.elapsed().as_secs_f64());
+    info!("Model appears to be {} [chat template: {}]", info.kind(), if info.chat_template.is_some() { "embedded" } else { "none" });
+    info!("Peak memory after loading: {}", profile::format_mib(profile::peak_rss()));
+    (ret, info)
+}
+
+/// Load a tokenizer from a `tokenizer.json` file, independently of any model.
+/// If the file does not exist, a byte-level BPE tokenizer is instead assembled from `vocab.json` and `merges.txt` in the same directory,
+/// with added tokens from `tokenizer_config.json` if it is present, as some model repositories ship these in place of `tokenizer.json`.
+pub fn load_tokenizer(path: &str) -> Result<Tokenizer, GenError> {
+    trace!("Attempting to create tokenizer...");
+    let path = Path::new(path);
+    let ret = if path.exists() {
+        Tokenizer::from_file(path).map_err(GenError::Tokenizer)?
+    } else {
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        debug!("{} does not exist, looking for tokenizer component files in {}", path.display(), dir.display());
+        load_component_tokenizer(dir)?
+    };
+    trace!("Tokenizer loaded.");
+    Ok(ret)
+}
+
+fn load_component_tokenizer(dir: &Path) -> Result<Tokenizer, GenError> {
+    let (vocab, merges, config) = (dir.join("vocab.json"), dir.join("merges.txt"), dir.join("tokenizer_config.json"));
+    if !(vocab.exists() && merges.exists()) {
+        let mut present: Vec<String> = fs::read_dir(dir).map_err(GenError::Io)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| [".json", ".txt", ".model"].iter().any(|ext| n.ends_with(ext)))
+            .collect();
+        present.sort();
+        return Err(GenError::Tokenizer(format!(
+            "no supported tokenizer layout in {}: expected tokenizer.json, or vocab.json with merges.txt; found [{}]",
+            dir.display(), present.join(", ")
+        ).into()));
+    }
+
+    let bpe = BPE::from_file(&vocab.to_string_lossy(), &merges.to_string_lossy()).build().map_err(GenError::Tokenizer)?;
+    let mut ret = Tokenizer::new(bpe);
+    ret.with_pre_tokenizer(ByteLevel::default().add_prefix_space(false));
+    ret.with_decoder(ByteLevel::default());
+
+    if config.exists() {
+        let text = fs::read_to_string(&config).map_err(GenError::Io)?;
+        let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| GenError::Tokenizer(e.into()))?;
+        let added = value.get("added_tokens_decoder").and_then(|v| v.as_object()).into_iter().flat_map(|m| m.values());
+        let (special, normal): (Vec<AddedToken>, Vec<AddedToken>) = added
+            .filter_map(|t| {
+                let content = t.get("content")?.as_str()?;
+                let special = t.get("special").and_then(|s| s.as_bool()).unwrap_or(false);
+                Some(AddedToken::from(content.to_owned(), special))
+            })
+            .partition(|t| t.special);
+        debug!("Adding {} special and {} other tokens from {}", special.len(), normal.len(), config.display());
+        ret.add_special_tokens(&special);
+        ret.add_tokens(&normal);
+    }
+    Ok(ret)
+}
 
-    info!("Successfully loaded model: {} [{} tensors, {} bytes] in {}s", path, n_tensors, total_size_in_bytes, load_start.elapsed().as_secs());
-    (ret, eos_token_id)
+/// Render a sample prompt with `format`, and warn if the special tokens it uses are unknown to the tokenizer,
+/// or absent from the chat template embedded in the GGUF (which suggests the model was trained with a different template).
+fn validate_template(format: &PromptFormat, special: &SpecialTokens, chat_template: Option<&str>) {
+    let template = format.template;
+    let sample = make_prompt(format, "You are a helpful assistant.", "Hello!", None, false);
+    eprintln!("Sample prompt with template {:?}:\n{}{}", template, sample, format.generation_lead());
+
+    let mut ok = true;
+    for (token, id) in special.template.iter() {
+        if id.is_none() {
+            warn!("Template {:?} uses special token {:?}, which is not in the tokenizer vocabulary.", template, token);
+            ok = false;
+        }
+        if let Some(t) = chat_template.filter(|t| !t.contains(token.as_str())) {
+            trace!("GGUF chat template: {}", t);
+            warn!("Template {:?} uses special token {:?}, which the chat template embedded in the GGUF does not.", template, token);
+            ok = false;
+        }
+    }
+    if !ok {
+        warn!("The model was probably not trained with template {:?}; output quality may suffer.", template);
+    } else if chat_template.is_none() {
+        debug!("GGUF does not embed a chat template, template {:?} could only be checked against the tokenizer.", template);
+    }
 }
 
 impl QuantizedTextGenerator {
 	pub fn from_args(args: &CliArgs) -> Self {
-		let device = get_device(args.cpu);
+		if let Err(e) = args.validate_sampling() {
+			error!("Invalid sampling parameters: {}", e);
+			panic!("{e}");
+		}
+		let spec = args.device_spec();
+		let device = spec.resolve(args.prefer_gpu).unwrap_or_else(|e| {
+			error!("Failed to select device {:?}: {}", spec, e);
+			panic!("{e}");
+		});
 		debug!("Active Device: {:?}", device);
 
-		trace!("Attempting to create tokenizer...");
-		let raw_tokenizer = Tokenizer::from_file(args.tokenizer_json.as_ref().unwrap()).expect("Failed to create tokenizer.");
-		trace!("Tokenizer loaded.");
+		let mut profiler = args.profile.as_deref().map(Profiler::new);
+		let tokenizer_start = Instant::now();
+		let tokenizer_path = args.tokenizer_json.as_ref().unwrap();
+		let raw_tokenizer = load_tokenizer(tokenizer_path).unwrap_or_else(|e| {
+			error!("Failed to create tokenizer from {}", tokenizer_path);
+			panic!("{e}");
+		});
+		if let Some(p) = profiler.as_mut() {
+			p.record("load tokenizer", "load", tokenizer_start, None);
+		}
 
         // Unused.
 		// let _config: QMistralConfig = match args.config_option.as_deref() {
@@ -89,44 +920,452 @@ impl QuantizedTextGenerator {
 		// let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(args.model_path.clone(), &device).expect("Failed to create VarBuilder");
         // let model = QMistralModel::new(&config, vb).expect("Failed to load model.");
 
-        let (model, eos_meta) = load_model_infallible(&args.model_path, &device);
-        let eos = eos_meta.or(args.eos_token).unwrap_or_else(|| {
+        let (model, info) = load_model_infallible(args.model_path.as_ref().unwrap(), &device, !args.quiet, profiler.as_mut());
+        // Templates without special tokens read as plain text, which base models continue well enough.
+        match info.kind() {
+            ModelKind::Base if !args.raw && !args.template.special_tokens().is_empty() => warn!(
+                "The model appears to be a base model, which was not trained on chat template {:?}; consider --raw for plain completion, or the i-messenger template.",
+                args.template
+            ),
+            ModelKind::Instruct if args.raw => warn!("The model appears to be instruct-tuned, and may answer poorly without its chat template; consider dropping --raw."),
+            _ => {}
+        }
+        let by_name = |flag: &str, text: &Option<String>| text.as_deref().map(|t| token_id_of(&raw_tokenizer, t).unwrap_or_else(|e| {
+            error!("Cannot use {} {:?}: {}", flag, t, e);
+            panic!("{e}");
+        }));
+        // A token named by its text takes precedence over the GGUF, since it is unlikely to be given by mistake.
+        let eos = by_name("--eos-str", &args.eos_str).or(info.eos_token_id).or(args.eos_token).unwrap_or_else(|| {
             error!("GGUF does not define appropriate metadata, and neither was EOS supplied via arguments.");
             panic!("Failed to identify EOS token.");
         });
+        let bos = by_name("--bos-str", &args.bos_str);
+        debug!("EOS token: {}, forced BOS token: {:?}", eos, bos);
 
+        let unk = by_name("--unk-str", &args.unk_str)
+            .or(info.unk_token_id)
+            .or_else(|| ["<unk>", "[UNK]"].iter().find_map(|t| raw_tokenizer.token_to_id(t)));
+        debug!("Unknown token: {:?}", unk);
+        let special = SpecialTokens {
+            bos: bos.or(info.bos_token_id),
+            eos,
+            unk,
+            pad: info.pad_token_id,
+            fim: info.fim_token_ids,
+            template: args.template.special_tokens().iter().map(|t| (t.to_string(), raw_tokenizer.token_to_id(t))).collect()
+        };
+        debug!("Special tokens: {:?}", special);
+        // Without its turn markers, Gemma's format reads as plain text, so it is always checked.
+        if args.validate_template || matches!(args.template, ChatTemplate::Gemma) {
+            validate_template(&args.prompt_format(), &special, info.chat_template.as_deref());
+        }
         debug!("Using seed: {}", args.seed);
 
-        let logits_processor = LogitsProcessor::new(args.seed, Some(args.temperature), args.top_p);
-        
+        if let Some(n) = args.n_ctx {
+            if n > MAX_SEQ_LEN {
+                warn!("n-ctx of {} exceeds the longest sequence supported ({} tokens), using {}", n, MAX_SEQ_LEN, MAX_SEQ_LEN);
+            }
+            if info.context_length.is_some_and(|trained| n > trained) {
+                warn!("n-ctx of {} exceeds the model's trained context of {} tokens; output may degrade past it.", n, info.context_length.unwrap_or_default());
+            }
+        }
+        let n_ctx = info.usable_context(args.n_ctx);
+        debug!("Context size: {} tokens", n_ctx);
+
+        let repeat_last_n = match (args.repeat_last_frac, args.n_ctx.or(info.context_length)) {
+            (Some(frac), Some(n_ctx)) => {
+                // Generation never goes beyond MAX_SEQ_LEN, however long the model's context.
+                let n = ((n_ctx.min(MAX_SEQ_LEN) as f32 * frac) as usize).max(1);
+                debug!("Repeat penalty window: {} tokens ({} of {} context)", n, frac, n_ctx);
+                n
+            },
+            (Some(_), None) => {
+                warn!("GGUF does not specify a context length, using repeat_last_n of {} instead of repeat_last_frac.", args.repeat_last_n);
+                args.repeat_last_n
+            },
+            (None, _) => args.repeat_last_n
+        };
+
+        let mirostat = args.mirostat.map(|v| {
+            debug!("Using mirostat v{} [tau: {}, eta: {}]", v, args.mirostat_tau, args.mirostat_eta);
+            Mirostat::new(v, args.mirostat_tau, args.mirostat_eta, sampling::seeded_rng(args.rng, args.seed))
+        });
+        let negative_prompt = args.negative_prompt.as_deref().map(|neg| {
+            debug!("Using negative prompt with guidance scale {}", args.guidance_scale);
+            if args.raw {
+                neg.to_owned()
+            } else {
+                make_prompt(&args.prompt_format(), args.sysprompt.as_deref().unwrap_or_default(), neg, None, args.normalize_prompt)
+            }
+        });
+
+        let cache = args.cache_dir.as_ref().filter(|_| !args.no_cache).and_then(|dir| {
+            if args.save_state.is_some() {
+                warn!("Not using the completion cache, since --save-state needs the generated tokens, which are not cached.");
+                return None;
+            }
+            if args.continue_after_stop {
+                warn!("Not using the completion cache, since --continue-after-stop continues from the generated tokens, which are not cached.");
+                return None;
+            }
+            CompletionCache::open(dir).map_err(|e| error!("Failed to open completion cache {}, cause: \"{}\"; not caching", dir, e)).ok()
+        });
+        let cache_settings = format!(
+            "{} {:016x} {} {:?} {:?} {:?} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"), info.model_hash, n_ctx, args.tokenizer_json,
+            (args.on_unk, bos, &special),
+            (args.seed, args.rng, args.tfs, args.typical, args.mirostat, args.mirostat_tau, args.mirostat_eta, args.greedy_with_jitter),
+            (args.structural_temperature, &args.structural_chars),
+            (&negative_prompt, args.guidance_scale, args.repeat_penalty, repeat_last_n, args.repeat_generated_only, args.no_repeat_ngram_size, args.beams, args.stop_after_token),
+            (args.keep_special_tokens, args.min_tokens_ignore_stop, args.eos_bias, args.eos_bias_tokens, args.strict_finite)
+        );
+
         Self {
             model,
-            tokenizer: raw_tokenizer,
-            logits_processor,
+            tokenizer: Box::new(raw_tokenizer),
+            sampler: BaseSampler::new(args.rng, args.seed, args.temperature, args.top_p),
+            params: SamplingParams::from_args(args),
+            seed: args.seed,
+            rng: args.rng,
+            tfs: args.tfs,
+            typical: args.typical,
+            mirostat,
+            greedy_jitter: args.greedy_with_jitter.map(|eps| GreedyJitter::new(eps, sampling::seeded_rng(args.rng, args.seed))),
+            structural: args.structural_temperature.map(|t| StructuralTemperature::new(t, args.structural_chars.clone())),
+            negative_prompt,
+            guidance_scale: args.guidance_scale,
             repeat_penalty: args.repeat_penalty,
-            repeat_last_n: args.repeat_last_n,
+            repeat_last_n,
+            repeat_generated_only: args.repeat_generated_only,
+            no_repeat_ngram_size: args.no_repeat_ngram_size.filter(|n| *n > 0),
+            beams: args.beams,
+            stop_after_token: args.stop_after_token,
+            dump_tokens: args.dump_tokens,
+            skip_special: !args.keep_special_tokens,
+            strict_finite: args.strict_finite,
+            min_tokens_ignore_stop: args.min_tokens_ignore_stop,
+            forward_retries: args.forward_retries,
+            profiler,
+            eos_bias: args.eos_bias,
+            eos_bias_tokens: args.eos_bias_tokens,
+            sample_on_cpu: args.sample_on_cpu,
+            retry_on_nan: args.retry_on_nan,
             device,
-            eos
+            n_ctx,
+            special,
+            bos,
+            on_unk: args.on_unk,
+            timeout: args.timeout.map(Duration::from_secs_f64),
+            model_hash: info.model_hash,
+            cache,
+            cache_settings,
+            replay_log: args.replay_log.clone(),
+            token_hook: None,
+            name: info.name.clone(),
+            architecture: info.architecture.clone(),
+            sampled: false,
+            context: Vec::new(),
+            resume_from: None
         }
 	}
 
+    /// The model's name, from the GGUF metadata, if it has one.
+    pub fn model_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The model's architecture (e.g. `llama`), from the GGUF metadata.
+    pub fn architecture(&self) -> Option<&str> {
+        self.architecture.as_deref()
+    }
+
+    /// IDs of the special tokens (BOS, EOS, and the chat template's markers), as resolved when the generator was created.
+    pub fn special_tokens(&self) -> &SpecialTokens {
+        &self.special
+    }
+
+    /// The tokenizer used by this generator, for token counting, truncation and the like.
+    pub fn tokenizer(&self) -> &dyn TextTokenizer {
+        self.tokenizer.as_ref()
+    }
+
+    /// Replace the tokenizer, e.g. with a SentencePiece one. Its vocabulary must match the model's; the IDs of the EOS and other
+    /// special tokens, resolved when the generator was created, are kept.
+    pub fn set_tokenizer(&mut self, tokenizer: Box<dyn TextTokenizer + Send>) {
+        self.tokenizer = tokenizer;
+    }
+
+    /// Call `hook` with every sampled token, before it is added to the context, to accept, replace or resample it,
+    /// e.g. to filter words or enforce a format. `None` (the default) accepts every token.
+    ///
+    /// The hook runs on every step of generation, so it should be cheap. The logits are those the token was sampled from, after
+    /// penalties and filters, and may be on the GPU; reading them copies the whole vocabulary to host memory. A resample costs
+    /// another sampling step (but no forward pass), and after a few in a row the last sampled token is kept. Generations with a hook
+    /// are not cached, since the hook may change their output.
+    pub fn set_token_hook(&mut self, hook: Option<TokenHook>) {
+        self.token_hook = hook;
+    }
+
+    /// The sampling parameters used by invocations which do not pass their own.
+    pub fn params(&self) -> &SamplingParams {
+        &self.params
+    }
+
+    /// Write the recorded profile, if profiling.
+    pub fn write_profile(&self) {
+        if let Some(p) = &self.profiler {
+            p.write();
+        }
+    }
+
+    /// Discard all state carried between invocations: the token context, and the running state of the sampler.
+    pub fn reset(&mut self) {
+        self.context.clear();
+        self.resume_from = None;
+        if let Some(m) = self.mirostat.as_mut() {
+            m.reset();
+        }
+    }
+
+    /// Run a short throwaway generation, so that lazy initialization (such as CUDA kernel loading) does not slow the first real one.
+    /// It runs on a copy of the model, choosing tokens greedily, so the context, KV cache and samplers are left as they were.
+    pub fn warmup(&mut self) -> Result<Duration, GenError> {
+        let start = Instant::now();
+        let mut model = self.model.clone();
+        let mut tokens = self.tokenizer.encode(WARMUP_PROMPT, true).map_err(GenError::Tokenizer)?;
+        if tokens.is_empty() {
+            tokens.push(self.special.eos);
+        }
+        let mut seqoff = 0;
+        let mut input = tokens.clone();
+        for _ in 0..=WARMUP_STEPS {
+            let t = Tensor::new(input.as_slice(), &self.device)?.unsqueeze(0)?;
+            // Reading the logits back waits for the device to finish.
+            let logits = model.forward(&t, seqoff)?.squeeze(0)?.to_dtype(candle_core::DType::F32)?.to_vec1::<f32>()?;
+            let next = sampling::argmax(&logits);
+            seqoff += input.len();
+            input = vec![next];
+        }
+        let elapsed = start.elapsed();
+        debug!("Warmed up in {:.3}s", elapsed.as_secs_f64());
+        Ok(elapsed)
+    }
+
+    /// Restore the samplers' random number generators to their state after loading.
+    fn reseed(&mut self) {
+        self.sampler = BaseSampler::new(self.rng, self.seed, self.params.temperature, self.params.top_p);
+        if let Some(m) = self.mirostat.as_mut() {
+            m.reseed(sampling::seeded_rng(self.rng, self.seed));
+        }
+        if let Some(j) = self.greedy_jitter.as_mut() {
+            j.reseed(sampling::seeded_rng(self.rng, self.seed));
+        }
+        self.sampled = false;
+    }
+
+    /// Write the token context of the most recent invocation to `path`.
+    /// Failure is logged, but not fatal, since the generated output has already been produced.
+    pub fn save_state(&self, path: &str) {
+        let state = GenerationState { model_hash: self.model_hash, tokens: self.context.clone() };
+        let result = File::create(path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(BufWriter::new(f), &state).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => info!("Saved {} tokens of context to {}", state.tokens.len(), path),
+            Err(e) => error!("Failed to save generation state to {}, cause: \"{}\"", path, e)
+        }
+    }
+
+    /// Restore a token context saved by `save_state`, which the next invocation will continue from.
+    /// The KV cache is not part of the state, so the restored context is prefilled again on the next invocation.
+    /// If any errors occur, or the state was saved with a different model, log and panic.
+    pub fn load_state_infallible(&mut self, path: &str) {
+        let file = File::open(path).unwrap_or_else(|e| {
+            error!("Failed to open generation state file {}", path);
+            panic!("{e:?}");
+        });
+        let state: GenerationState = serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+            error!("Failed to parse generation state file {}", path);
+            panic!("{e:?}");
+        });
+        if state.model_hash != self.model_hash {
+            error!("Generation state {} was saved with a different model [{:016x}], current model is [{:016x}]", path, state.model_hash, self.model_hash);
+            panic!("Generation state does not match loaded model.");
+        }
+        info!("Restored {} tokens of context from {}", state.tokens.len(), path);
+        self.resume_from = Some(state.tokens);
+    }
+
     /// Invoke the LLM and yield generated output.
-    /// If any errors occur, log and panic.
-    pub fn invoke_infallible(&mut self, prompt: &str) -> String {
+    pub fn invoke(&mut self, prompt: &str) -> Result<Completion, GenError> {
+        let params = self.params.clone();
+        self.generate(prompt, &params, None, None)
+    }
+
+    /// Invoke the LLM with sampling parameters other than the defaults.
+    /// When the temperature or top-p differ from the defaults, a sampler freshly seeded with the generator's seed is used for this invocation.
+    /// Mirostat and jittered greedy decoding, when enabled, take only the temperature (mirostat) or nothing (jitter) from `params`.
+    pub fn invoke_with_params(&mut self, prompt: &str, params: &SamplingParams) -> Result<Completion, GenError> {
+        params.validate().map_err(GenError::InvalidParams)?;
+        self.generate(prompt, params, None, None)
+    }
+
+    /// Invoke the LLM, stopping early once `cancel` is set. The output generated until then is returned, with `FinishReason::Cancelled`.
+    pub fn invoke_cancellable(&mut self, prompt: &str, cancel: &AtomicBool) -> Result<Completion, GenError> {
+        let params = self.params.clone();
+        self.generate(prompt, &params, None, Some(cancel))
+    }
+
+    /// Invoke the LLM, calling `cb` with each piece of text as soon as it is generated.
+    /// The returned completion still contains the full generated output.
+    pub fn talk_and_map<F>(&mut self, prompt: &str, mut cb: F) -> Result<Completion, GenError>
+    where F: FnMut(&str) {
+        let params = self.params.clone();
+        self.generate(prompt, &params, Some(&mut cb), None)
+    }
+
+    /// As `talk_and_map`, but stopping early once `cancel` is set, as with `invoke_cancellable`.
+    pub fn talk_and_map_cancellable<F>(&mut self, prompt: &str, cancel: &AtomicBool, mut cb: F) -> Result<Completion, GenError>
+    where F: FnMut(&str) {
+        let params = self.params.clone();
+        self.generate(prompt, &params, Some(&mut cb), Some(cancel))
+    }
+
+    /// Continue generating from the token context of the most recent invocation, as though its output had not ended (e.g. past a stop sequence).
+    /// As with a restored state, the context is prefilled again. `on_text`, if given, receives each piece of text as it is generated.
+    /// A completion served from the cache leaves no context, so continuing it fails with `GenError::EmptyPrompt`; disable the cache to continue.
+    pub fn continue_cancellable(&mut self, params: &SamplingParams, cancel: &AtomicBool, on_text: Option<&mut dyn FnMut(&str)>) -> Result<Completion, GenError> {
+        params.validate().map_err(GenError::InvalidParams)?;
+        if self.context.is_empty() {
+            return Err(GenError::EmptyPrompt);
+        }
+        self.resume_from = Some(self.context.clone());
+        self.generate("", params, on_text, Some(cancel))
+    }
+
+    /// Run a recorded generation again, from its recorded input and with its recorded parameters, returning the tokens chosen this time.
+    /// Entries must be replayed in the order they were recorded, from a freshly loaded generator, since sampling may carry state between generations.
+    pub fn replay(&mut self, entry: &ReplayEntry) -> Result<Vec<u32>, GenError> {
+        entry.params.validate().map_err(GenError::InvalidParams)?;
+        if entry.model_hash != self.model_hash {
+            warn!("Replay entry was recorded with another model [{:016x}, loaded {:016x}]; tokens will likely differ.", entry.model_hash, self.model_hash);
+        }
+        if entry.settings != self.cache_settings {
+            warn!("Replay entry was recorded with other settings; tokens may differ.\n  recorded: {}\n  current:  {}", entry.settings, self.cache_settings);
+        }
+        if entry.input.is_empty() {
+            return Err(GenError::EmptyPrompt);
+        }
+        if entry.fresh_rng && self.sampled {
+            self.reseed();
+        }
+        self.resume_from = Some(entry.input.clone());
+        self.generate("", &entry.params, None, None)?;
+        Ok(self.context[entry.input.len().min(self.context.len())..].to_vec())
+    }
+
+    fn generate(&mut self, prompt: &str, params: &SamplingParams, mut on_text: Option<&mut dyn FnMut(&str)>, cancel: Option<&AtomicBool>) -> Result<Completion, GenError> {
+        // Only a continuation of a restored context may have no prompt at all.
+        let continuation = prompt.is_empty() && self.resume_from.is_some();
+        if prompt.trim().is_empty() && !continuation {
+            return Err(GenError::EmptyPrompt);
+        }
+        let mut call_sampler = (params.temperature != self.params.temperature || params.top_p != self.params.top_p)
+            .then(|| BaseSampler::new(self.rng, self.seed, params.temperature, params.top_p));
+
+        // A per-invocation sampler is seeded afresh, and greedy decoding draws nothing, so only the shared samplers carry state between invocations.
+        let shared_rng = self.beams == 1
+            && (self.mirostat.is_some() || self.greedy_jitter.is_some() || (call_sampler.is_none() && params.temperature > 1e-7));
+        let cache_key = self.cache.as_ref()
+            .filter(|_| self.resume_from.is_none() && self.token_hook.is_none() && !(shared_rng && self.sampled))
+            .map(|_| CompletionCache::key(&[&self.cache_settings, &format!("{:?}", params), prompt]));
+        if let Some((key, c)) = cache_key.and_then(|k| Some((k, self.cache.as_ref()?.get(k)?))) {
+            debug!("Using cached completion [{:016x}]", key);
+            // The tokens behind a cached completion are not stored, so there is no context left to continue or save.
+            self.context.clear();
+            if let Some(cb) = on_text.as_mut() {
+                cb(&c.text);
+            }
+            return Ok(c);
+        }
+        let fresh_rng = !self.sampled;
+        self.sampled |= shared_rng;
+
+        // Continue from a restored context, if any. Special tokens (BOS) are only added at the start of a context.
+        let mut tokens = self.resume_from.take().unwrap_or_default();
+        let mut prefix_len = tokens.len();
+
         // Encode the prompt.
-        let mut tokens = self.tokenizer.encode(prompt, true).unwrap_or_else(|e| {
-            error!("Failed to encode prompt {prompt} with tokenizer");
-            panic!("{e:?}");
-        }).get_ids().to_vec();
+        tokens.extend_from_slice(&self.tokenizer.encode(prompt, prefix_len == 0).map_err(GenError::Tokenizer)?);
+        if let Some(bos) = self.bos.filter(|b| prefix_len == 0 && tokens.first() != Some(b)) {
+            tokens.insert(0, bos);
+        }
+        // Leave room for at least one generated token, dropping the start of the context if needed.
+        if tokens.len() >= self.n_ctx {
+            let excess = tokens.len() + 1 - self.n_ctx;
+            warn!("Prompt of {} tokens does not fit in the context of {} tokens, dropping its first {} tokens.", tokens.len(), self.n_ctx, excess);
+            tokens.drain(..excess);
+            // Tokens are dropped from the restored context first, so the prompt now starts that much earlier.
+            prefix_len = prefix_len.saturating_sub(excess);
+        }
+        if tokens.len() == prefix_len && !continuation {
+            return Err(GenError::EmptyPrompt);
+        }
+        if let Some(unk) = self.special.unk.filter(|_| !matches!(self.on_unk, UnkPolicy::Ignore)) {
+            let n_unk = tokens[prefix_len..].iter().filter(|t| **t == unk).count();
+            if n_unk > 0 {
+                if let UnkPolicy::Error = self.on_unk {
+                    return Err(GenError::UnknownTokens(n_unk));
+                }
+                warn!("Prompt contains {} unknown tokens; the tokenizer may not match the model, or the text contains unsupported characters.", n_unk);
+            }
+        }
 
         trace!("Tokenized prompt.");
 
         trace!("Starting generation.");
         let start_time = Instant::now();
+        let prompt_tokens = tokens.len();
+        let max_stop_len = params.stop.iter().map(|s| s.len()).max().unwrap_or(0);
         let mut generation_count = 0;
         let mut flag = true;
+        let mut decoder = IncrementalDecoder::new(prompt_tokens, self.skip_special);
+        let mut warned_unknown = false;
+        let mut token_latencies = Vec::new();
+        // Occurrences of the token generation stops after, when set.
+        let mut stop_token_count = 0;
+        // For guidance, a copy of the model (sharing weights, with its own KV cache) runs over the negative prompt, followed by the same generated tokens.
+        let mut negative = match &self.negative_prompt {
+            Some(neg) => {
+                let neg_tokens = self.tokenizer.encode(neg.as_str(), true).map_err(GenError::Tokenizer)?;
+                Some((self.model.clone(), neg_tokens))
+            },
+            None => None
+        };
+        // Streamed text which has been decoded, but not yet passed on.
+        let mut pending = String::new();
+        // Bytes of text passed to `on_text` so far.
+        let mut emitted = 0;
+        // Stop sequences only count from this token on.
+        let stop_floor = match params.min_tokens {
+            Some(n) if self.min_tokens_ignore_stop => prompt_tokens + n,
+            _ => prompt_tokens
+        };
 
-        loop {
+        // Penalties and sampling run wherever the logits are, unless moved to CPU (a no-op if the model already runs on CPU).
+        let mut ngrams = self.no_repeat_ngram_size.map(NgramBlocker::new);
+        let sampling_device = if self.sample_on_cpu { &Device::Cpu } else { &self.device };
+
+        let finish_reason = loop {
+            // Beam search runs a decoding loop of its own, in place of this one.
+            if self.beams > 1 {
+                let (context, reason) = self.beam_search(&tokens, params, stop_floor, cancel, start_time, &mut token_latencies)?;
+                tokens = context;
+                generation_count = tokens.len() - prompt_tokens;
+                break reason;
+            }
+            let step_start = Instant::now();
+            let prefill = flag;
             let (context, seqoff) = if flag {
                 flag = false;
                 (tokens.as_slice(), 0)
@@ -135,20 +1374,151 @@ impl QuantizedTextGenerator {
                 (&tokens[off..], off)
             };
 
-            let input = Tensor::new(context, &self.device).and_then(|t| t.unsqueeze(0)).expect("Could not initialize context tensor.");
-            let mut logits = self.model.forward(&input, seqoff)
-                        .and_then(|t| t.squeeze(0))
-                        .and_then(|t| t.squeeze(0))
-                        .and_then(|t| t.to_dtype(candle_core::DType::F32))
-                        .expect("Failed to obtain logits.");
+            // To retry a step with non-finite logits, the models are copied (cheaply: weights are shared) before their KV caches are extended.
+            let mut snapshot = (self.retry_on_nan && !self.device.is_cpu())
+                .then(|| (self.model.clone(), negative.as_ref().map(|(m, _)| m.clone())));
+            let mut step_device = sampling_device;
+            let mut logits = loop {
+                let input = Tensor::new(context, &self.device).and_then(|t| t.unsqueeze(0))?;
+                let mut logits = forward_with_retries(&mut self.model, &input, seqoff, self.forward_retries)
+                            .and_then(|t| t.squeeze(0))
+                            .and_then(|t| t.squeeze(0))
+                            .and_then(|t| t.to_device(step_device))
+                            .and_then(|t| t.to_dtype(candle_core::DType::F32))?;
+                if let Some((neg_model, neg_tokens)) = negative.as_mut() {
+                    let (neg_context, neg_seqoff) = if generation_count == 0 {
+                        (neg_tokens.as_slice(), 0)
+                    } else {
+                        let off = neg_tokens.len().saturating_sub(1);
+                        (&neg_tokens[off..], off)
+                    };
+                    let neg_input = Tensor::new(neg_context, &self.device).and_then(|t| t.unsqueeze(0))?;
+                    let neg_logits = forward_with_retries(neg_model, &neg_input, neg_seqoff, self.forward_retries)
+                            .and_then(|t| t.squeeze(0))
+                            .and_then(|t| t.squeeze(0))
+                            .and_then(|t| t.to_device(step_device))
+                            .and_then(|t| t.to_dtype(candle_core::DType::F32))?;
+                    // neg + scale * (pos - neg)
+                    logits = logits.sub(&neg_logits)?.affine(self.guidance_scale, 0.0)?.add(&neg_logits)?;
+                }
+                match snapshot.take() {
+                    Some((model, neg_model)) if !all_finite(&logits)? => {
+                        warn!("Logits for generated token {} are not all finite on {:?}, retrying the step and sampling it on CPU.", generation_count, self.device);
+                        self.model = model;
+                        if let (Some((m, _)), Some(neg_model)) = (negative.as_mut(), neg_model) {
+                            *m = neg_model;
+                        }
+                        step_device = &Device::Cpu;
+                    },
+                    _ => break logits
+                }
+            };
+            let fallback = finite_fallback(&logits, self.strict_finite, generation_count)?;
             if self.repeat_penalty != 1.0 {
-                let repeat_context = &tokens[tokens.len().saturating_sub(self.repeat_last_n)..];
-                logits = candle_transformers::utils::apply_repeat_penalty(&logits, self.repeat_penalty, repeat_context).expect("Could not apply repeat penalty");
+                let window_start = tokens.len().saturating_sub(self.repeat_last_n);
+                let window_start = if self.repeat_generated_only { window_start.max(prompt_tokens) } else { window_start };
+                let repeat_context = &tokens[window_start..];
+                logits = candle_transformers::utils::apply_repeat_penalty(&logits, self.repeat_penalty, repeat_context)?;
             }
             
-            let next_token = self.logits_processor.sample(&logits).expect("Could not sample token from logits");
+            // Filters are applied in order: repeat penalty, EOS bias, n-gram blocking, tail-free, locally typical, structural temperature,
+            // and then the sampler itself.
+            let eos_bias = self.eos_bias.filter(|_| generation_count < self.eos_bias_tokens);
+            let suppress_eos = params.min_tokens.is_some_and(|n| generation_count < n);
+            if eos_bias.is_some() || suppress_eos || ngrams.is_some() || self.tfs.is_some() || self.typical.is_some() || self.mirostat.is_some() || self.greedy_jitter.is_some()
+                || self.structural.is_some()
+            {
+                let mut values = logits.to_vec1::<f32>()?;
+                if let Some(bias) = eos_bias {
+                    if let Some(l) = values.get_mut(self.special.eos as usize) {
+                        *l -= bias;
+                    }
+                }
+                if suppress_eos {
+                    if let Some(l) = values.get_mut(self.special.eos as usize) {
+                        *l = f32::NEG_INFINITY;
+                    }
+                }
+                if let Some(b) = ngrams.as_ref() {
+                    b.block(&tokens[prompt_tokens..], &mut values);
+                }
+                if let Some(z) = self.tfs {
+                    sampling::tail_free(&mut values, z);
+                }
+                if let Some(p) = self.typical {
+                    sampling::locally_typical(&mut values, p);
+                }
+                if let Some(st) = self.structural.as_mut() {
+                    let tokenizer = &self.tokenizer;
+                    st.apply(&mut values, params.temperature, |t| tokenizer.decode(&[t], false).ok());
+                }
+                logits = Tensor::new(values, &Device::Cpu)?;
+            }
+
+            let sample_start = Instant::now();
+            if let Some(p) = self.profiler.as_mut() {
+                p.record(if prefill { "prefill" } else { "decode" }, "forward", step_start, Some(serde_json::json!({ "tokens": context.len(), "position": seqoff })));
+            }
+
+            let mut resamples = 0;
+            let next_token = loop {
+                let sampled = match (fallback, self.mirostat.as_mut(), self.greedy_jitter.as_mut(), call_sampler.as_mut()) {
+                    (Some(t), _, _, _) => t,
+                    (None, Some(m), _, _) => m.sample(&logits.to_vec1::<f32>()?, params.temperature),
+                    (None, None, Some(j), _) => j.sample(&logits.to_vec1::<f32>()?),
+                    (None, None, None, Some(s)) => s.sample(&logits)?,
+                    (None, None, None, None) => self.sampler.sample(&logits)?
+                };
+                let Some(hook) = self.token_hook.as_mut() else {
+                    break sampled;
+                };
+                match hook(sampled, &logits) {
+                    TokenDecision::Accept => break sampled,
+                    TokenDecision::Replace(t) => break t,
+                    // A fallback token is chosen without sampling, so resampling would choose it again.
+                    TokenDecision::Resample if fallback.is_some() || resamples >= MAX_TOKEN_RESAMPLES => {
+                        warn!("Token hook rejected token {} at step {}, but it cannot be resampled again; keeping it.", sampled, generation_count);
+                        break sampled;
+                    },
+                    TokenDecision::Resample => {
+                        resamples += 1;
+                        let mut values = logits.to_vec1::<f32>()?;
+                        if let Some(l) = values.get_mut(sampled as usize) {
+                            *l = f32::NEG_INFINITY;
+                        }
+                        logits = Tensor::new(values, logits.device())?;
+                    }
+                }
+            };
             tokens.push(next_token);
+            token_latencies.push(step_start.elapsed());
+            if !warned_unknown && self.tokenizer.id_to_token(next_token).is_none() {
+                warn!(
+                    "Sampled token {}, which the tokenizer ({} tokens) cannot decode; it is shown as {:?}. The model and tokenizer likely do not match, `doctor` compares their vocabularies.",
+                    next_token, self.tokenizer.vocab_size(), UNKNOWN_TOKEN_TEXT
+                );
+                warned_unknown = true;
+            }
+            if let Some(p) = self.profiler.as_mut() {
+                p.record("sample", "sample", sample_start, Some(serde_json::json!({ "token": next_token })));
+            }
+            if self.dump_tokens {
+                let piece = decode_lossy(&*self.tokenizer, &[next_token], false)?;
+                eprintln!("[{}] {} {:?}", generation_count, next_token, piece);
+            }
+            if let Some(b) = ngrams.as_mut() {
+                b.push(&tokens[prompt_tokens..]);
+            }
+            if let Some((_, neg_tokens)) = negative.as_mut() {
+                neg_tokens.push(next_token);
+            }
             generation_count += 1;
+
+            if on_text.is_some() {
+                if let Some(piece) = decoder.next(&*self.tokenizer, &tokens, false)? {
+                    pending.push_str(&piece);
+                }
+            }
             
             if (generation_count % DEBUG_TOKEN_COUNT) == 0 {
                 debug!("Got {} tokens so far.", generation_count);
@@ -157,24 +1527,533 @@ impl QuantizedTextGenerator {
                 }
             }
 
-            if next_token == self.eos {
-                break;
+            if next_token == self.special.eos {
+                break FinishReason::Eos;
+            }
+
+            if let Some((id, n)) = self.stop_after_token.filter(|(id, _)| *id == next_token) {
+                stop_token_count += 1;
+                if stop_token_count >= n {
+                    debug!("Token {} generated {} times, stopping.", id, n);
+                    break FinishReason::Stop;
+                }
+            }
+
+            // Every token decodes to at least one byte of text, so a stop sequence must lie in the last `max_stop_len` tokens.
+            if max_stop_len > 0 && tokens.len() > stop_floor {
+                let tail = &tokens[tokens.len().saturating_sub(max_stop_len).max(stop_floor)..];
+                let tail_text = decode_lossy(&*self.tokenizer, tail, self.skip_special)?;
+                if params.stop.iter().any(|s| tail_text.contains(s.as_str())) {
+                    break FinishReason::Stop;
+                }
+            }
+
+            if params.max_tokens.is_some_and(|n| generation_count >= n) || tokens.len() >= self.n_ctx {
+                break FinishReason::Length;
+            }
+
+            if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                break FinishReason::Cancelled;
+            }
+
+            if self.timeout.is_some_and(|t| start_time.elapsed() >= t) {
+                break FinishReason::Timeout;
+            }
+
+            if let Some(cb) = on_text.as_mut() {
+                // Hold back just enough text to contain the beginning of a stop sequence.
+                let mut n = pending.len().saturating_sub(max_stop_len.saturating_sub(1));
+                while !pending.is_char_boundary(n) {
+                    n -= 1;
+                }
+                if n > 0 {
+                    cb(&pending[..n]);
+                    pending.drain(..n);
+                    emitted += n;
+                }
+            }
+        };
+
+        // Length of the text before `stop_floor`, in which stop sequences are ignored.
+        let stop_floor_bytes = if stop_floor > prompt_tokens && finish_reason == FinishReason::Stop {
+            decode_lossy(&*self.tokenizer, &tokens[prompt_tokens..stop_floor.min(tokens.len())], self.skip_special)?.len()
+        } else {
+            0
+        };
+        if let Some(cb) = on_text.as_mut() {
+            if let Some(piece) = decoder.next(&*self.tokenizer, &tokens, true)? {
+                pending.push_str(&piece);
+            }
+            if finish_reason == FinishReason::Stop {
+                if let Some(pos) = find_stop(&pending, &params.stop, stop_floor_bytes.saturating_sub(emitted)) {
+                    pending.truncate(pos);
+                }
+            }
+            if !pending.is_empty() {
+                cb(&pending);
             }
         }
 
         trace!("Finished token generation.");
-        let t = start_time.elapsed().as_secs();
-        debug!("Genereated {} tokens in {}s [avg: {}t/s]", generation_count, t, generation_count as f64 / (t as f64));
+        if let Some(p) = self.profiler.as_mut() {
+            p.record("generate", "generate", start_time, Some(serde_json::json!({ "prompt_tokens": prompt_tokens, "generated_tokens": generation_count })));
+        }
+        let t = start_time.elapsed().as_secs_f64();
+        let tps = if t > 0.0 { generation_count as f64 / t } else { 0.0 };
+        debug!("Generated {} tokens in {:.3}s [avg: {:.2}t/s]", generation_count, t, tps);
         trace!("Decoding...");
-        self.tokenizer.decode(&tokens, true).unwrap_or_else(|e| {
-            error!("Failed to decode generated tokens: {tokens:?}");
-            panic!("Tokenizer decode resulted in error. {e:?}");
-        })[prompt.len()..].to_owned()
-    }
-    
-    /*
-	pub fn talk_and_map<F>(&mut self, prompt: &str, mut cb: F)
-	where F: FnMut(&str) {	
-	   unimplemented!("Will implement this once a mechanism to stream lines from tokens is established.");	
-	}*/
-}
\ No newline at end of file
+        let mut text = decode_generated(&*self.tokenizer, &tokens, prompt_tokens, self.skip_special)?;
+        if finish_reason == FinishReason::Stop {
+            if let Some(pos) = find_stop(&text, &params.stop, stop_floor_bytes) {
+                text.truncate(pos);
+            }
+        }
+        debug!("Finished generation, reason: {}", finish_reason);
+        debug!("Peak memory after generation: {}", profile::format_mib(profile::peak_rss()));
+        if let Some(path) = &self.replay_log {
+            let entry = ReplayEntry {
+                model_hash: self.model_hash,
+                settings: self.cache_settings.clone(),
+                params: params.clone(),
+                input: tokens[..prompt_tokens].to_vec(),
+                output: tokens[prompt_tokens..].to_vec(),
+                finish_reason,
+                fresh_rng
+            };
+            append_replay_entry(path, &entry);
+        }
+        self.context = tokens;
+        let completion = Completion {
+            text,
+            finish_reason,
+            prompt_tokens,
+            generated_tokens: generation_count,
+            elapsed: start_time.elapsed(),
+            token_latencies
+        };
+        // Cancelled or timed out completions depend on more than the prompt and settings.
+        if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+            if matches!(finish_reason, FinishReason::Eos | FinishReason::Length | FinishReason::Stop) {
+                cache.put(key, &completion);
+            }
+        }
+        Ok(completion)
+    }
+
+    /// Decode the prompt `tokens` with beam search: keep the `beams` most likely sequences, by cumulative log-probability, extending
+    /// each with its most likely tokens at every step, until as many sequences have finished. Returns the context ending with the
+    /// sequence of highest log-probability per token, among those finished (or cut short, by the length limit or otherwise), and
+    /// why it finished. Every beam extends a KV cache of its own. The time taken by every step is pushed to `latencies`.
+    fn beam_search(&mut self, tokens: &[u32], params: &SamplingParams, stop_floor: usize, cancel: Option<&AtomicBool>, start_time: Instant, latencies: &mut Vec<Duration>) -> Result<(Vec<u32>, FinishReason), GenError> {
+        if self.token_hook.is_some() {
+            warn!("The token hook is not called with beam search.");
+        }
+        let prompt_tokens = tokens.len();
+        let max_stop_len = params.stop.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut beams = vec![Beam { model: self.model.clone(), tokens: tokens.to_vec(), logprob: 0.0 }];
+        let mut finished: Vec<(Vec<u32>, f64, FinishReason)> = Vec::new();
+        let mut step = 0;
+        let cut_short = loop {
+            let step_start = Instant::now();
+            let mut candidates: Vec<(usize, u32, f64)> = Vec::new();
+            for (i, beam) in beams.iter_mut().enumerate() {
+                let off = if step == 0 { 0 } else { beam.tokens.len() - 1 };
+                let input = Tensor::new(&beam.tokens[off..], &self.device).and_then(|t| t.unsqueeze(0))?;
+                let mut logits = forward_with_retries(&mut beam.model, &input, off, self.forward_retries)
+                            .and_then(|t| t.squeeze(0))
+                            .and_then(|t| t.squeeze(0))
+                            .and_then(|t| t.to_device(&Device::Cpu))
+                            .and_then(|t| t.to_dtype(candle_core::DType::F32))?;
+                if self.repeat_penalty != 1.0 {
+                    let window_start = beam.tokens.len().saturating_sub(self.repeat_last_n);
+                    let window_start = if self.repeat_generated_only { window_start.max(prompt_tokens) } else { window_start };
+                    logits = candle_transformers::utils::apply_repeat_penalty(&logits, self.repeat_penalty, &beam.tokens[window_start..])?;
+                }
+                let mut values = logits.to_vec1::<f32>()?;
+                if values.iter().any(|l| !l.is_finite()) {
+                    if self.strict_finite {
+                        return Err(GenError::NonFiniteLogits(step));
+                    }
+                    values.iter_mut().filter(|l| !l.is_finite()).for_each(|l| *l = f32::NEG_INFINITY);
+                }
+                if params.min_tokens.is_some_and(|n| step < n) {
+                    if let Some(l) = values.get_mut(self.special.eos as usize) {
+                        *l = f32::NEG_INFINITY;
+                    }
+                }
+                // Twice as many candidates as beams, so that enough remain when some of them finish.
+                for (token, logprob) in sampling::top_k(&sampling::log_softmax(&values), 2 * self.beams) {
+                    candidates.push((i, token, beam.logprob + logprob as f64));
+                }
+            }
+            step += 1;
+
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+            let mut next = Vec::with_capacity(self.beams);
+            for (i, token, logprob) in candidates.into_iter().filter(|c| c.2.is_finite()) {
+                if next.len() == self.beams {
+                    break;
+                }
+                let mut tokens = beams[i].tokens.clone();
+                tokens.push(token);
+                if token == self.special.eos {
+                    finished.push((tokens, logprob, FinishReason::Eos));
+                    continue;
+                }
+                if self.stop_after_token.is_some_and(|(id, n)| id == token && tokens[prompt_tokens..].iter().filter(|t| **t == id).count() >= n) {
+                    finished.push((tokens, logprob, FinishReason::Stop));
+                    continue;
+                }
+                if max_stop_len > 0 && tokens.len() > stop_floor {
+                    let tail = &tokens[tokens.len().saturating_sub(max_stop_len).max(stop_floor)..];
+                    let tail_text = decode_lossy(&*self.tokenizer, tail, self.skip_special)?;
+                    if params.stop.iter().any(|s| tail_text.contains(s.as_str())) {
+                        finished.push((tokens, logprob, FinishReason::Stop));
+                        continue;
+                    }
+                }
+                next.push(Beam { model: beams[i].model.clone(), tokens, logprob });
+            }
+            beams = next;
+            latencies.push(step_start.elapsed());
+            trace!("Beam search step {}: {} beams, {} finished", step, beams.len(), finished.len());
+
+            if finished.len() >= self.beams || beams.is_empty() {
+                break None;
+            }
+            if params.max_tokens.is_some_and(|n| step >= n) || beams.iter().any(|b| b.tokens.len() >= self.n_ctx) {
+                break Some(FinishReason::Length);
+            }
+            if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+                break Some(FinishReason::Cancelled);
+            }
+            if self.timeout.is_some_and(|t| start_time.elapsed() >= t) {
+                break Some(FinishReason::Timeout);
+            }
+        };
+        if let Some(reason) = cut_short {
+            finished.extend(beams.into_iter().map(|b| (b.tokens, b.logprob, reason)));
+        }
+        // Ranked per token, so that shorter sequences are not favoured for having fewer log-probabilities summed.
+        let per_token = |(tokens, logprob, _): &(Vec<u32>, f64, FinishReason)| logprob / (tokens.len() - prompt_tokens) as f64;
+        let (tokens, logprob, reason) = finished.into_iter()
+            .max_by(|a, b| per_token(a).total_cmp(&per_token(b)))
+            .ok_or(GenError::NonFiniteLogits(step - 1))?;
+        debug!("Beam search chose a sequence of {} tokens, with log-probability {:.3}", tokens.len() - prompt_tokens, logprob);
+        Ok((tokens, reason))
+    }
+}
+
+/// A candidate sequence of beam search: the context so far, its log-probability, and the model with the context in its KV cache.
+struct Beam {
+    model: ModelWeights,
+    tokens: Vec<u32>,
+    logprob: f64
+}
+
+/// Append `entry` to the replay log at `path`. Failure is logged, but not fatal, since the completion has been produced regardless.
+fn append_replay_entry(path: &str, entry: &ReplayEntry) {
+    let result = serde_json::to_string(entry)
+        .map_err(std::io::Error::other)
+        .and_then(|line| {
+            let mut f = fs::OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(f, "{}", line)
+        });
+    if let Err(e) = result {
+        warn!("Failed to append to replay log {}, cause: \"{}\"", path, e);
+    }
+}
+
+/// Generation of completions for prompts, as the front end uses it, so that its output handling does not depend on a loaded model.
+pub trait TextGenerator {
+    /// Generate a completion for `prompt`, with the default sampling parameters, stopping early once `cancel` is set.
+    /// When `on_text` is given, it receives each piece of text as soon as it is generated; the completion still contains the full output.
+    fn complete(&mut self, prompt: &str, cancel: &AtomicBool, on_text: Option<&mut dyn FnMut(&str)>) -> Result<Completion, GenError>;
+}
+
+impl TextGenerator for QuantizedTextGenerator {
+    fn complete(&mut self, prompt: &str, cancel: &AtomicBool, on_text: Option<&mut dyn FnMut(&str)>) -> Result<Completion, GenError> {
+        let params = self.params.clone();
+        self.generate(prompt, &params, on_text, Some(cancel))
+    }
+}
+
+/// Text which stands in for tokens the tokenizer cannot decode.
+const UNKNOWN_TOKEN_TEXT: &str = "<unk>";
+
+/// Decode `tokens`, replacing IDs unknown to the tokenizer with [`UNKNOWN_TOKEN_TEXT`] rather than dropping them.
+/// A model whose vocabulary is slightly larger than its tokenizer's may sample such IDs.
+fn decode_lossy(tokenizer: &dyn TextTokenizer, tokens: &[u32], skip_special: bool) -> Result<String, GenError> {
+    let known = |t: &u32| tokenizer.id_to_token(*t).is_some();
+    if tokens.iter().all(known) {
+        return tokenizer.decode(tokens, skip_special).map_err(GenError::Tokenizer);
+    }
+    let mut ret = String::new();
+    for run in tokens.split_inclusive(|t| !known(t)) {
+        let (run, unknown) = match run.split_last() {
+            Some((last, rest)) if !known(last) => (rest, true),
+            _ => (run, false)
+        };
+        ret.push_str(&tokenizer.decode(run, skip_special).map_err(GenError::Tokenizer)?);
+        if unknown {
+            ret.push_str(UNKNOWN_TOKEN_TEXT);
+        }
+    }
+    Ok(ret)
+}
+
+/// Decode the text generated after the first `prompt_tokens` of `tokens`.
+fn decode_generated(tokenizer: &dyn TextTokenizer, tokens: &[u32], prompt_tokens: usize, skip_special: bool) -> Result<String, GenError> {
+    let decoded = decode_lossy(tokenizer, tokens, skip_special)?;
+    let decoded_prompt = decode_lossy(tokenizer, &tokens[..prompt_tokens], skip_special)?;
+    // Decoding the whole context preserves spacing at the start of the generated text. The decoded prompt need not match the prompt
+    // (special tokens are skipped, unicode may be normalized), so fall back to decoding only the generated tokens when it is not a prefix.
+    match decoded.strip_prefix(decoded_prompt.as_str()) {
+        Some(t) => Ok(t.to_owned()),
+        None => {
+            debug!("Decoded context does not start with the decoded prompt, decoding generated tokens alone.");
+            decode_lossy(tokenizer, &tokens[prompt_tokens..], skip_special)
+        }
+    }
+}
+
+/// Incrementally decodes generated tokens into text.
+/// Each token is decoded along with the previous one, so that spacing between tokens is preserved,
+/// and text is held back while it ends in an incomplete character.
+struct IncrementalDecoder {
+    prev_index: usize,
+    current_index: usize,
+    skip_special: bool
+}
+
+impl IncrementalDecoder {
+    fn new(offset: usize, skip_special: bool) -> Self {
+        Self { prev_index: offset, current_index: offset, skip_special }
+    }
+
+    /// Decode the text added by tokens since the last call. When `flush` is set, incomplete characters are released too.
+    fn next(&mut self, tokenizer: &dyn TextTokenizer, tokens: &[u32], flush: bool) -> Result<Option<String>, GenError> {
+        let prev_text = decode_lossy(tokenizer, &tokens[self.prev_index..self.current_index], self.skip_special)?;
+        let text = decode_lossy(tokenizer, &tokens[self.prev_index..], self.skip_special)?;
+        if text.len() > prev_text.len() && (flush || !text.ends_with(char::REPLACEMENT_CHARACTER)) {
+            if let Some(piece) = text.get(prev_text.len()..) {
+                self.prev_index = self.current_index;
+                self.current_index = tokens.len();
+                return Ok(Some(piece.to_owned()));
+            }
+        }
+        Ok(None)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+    use clap::Parser;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use std::sync::OnceLock;
+    use crate::tokenizer::TokenizerError;
+
+    /// Special tokens of the tiny model's vocabulary, which continues with one token per byte.
+    const SPECIALS: [&str; 3] = ["<unk>", "<s>", "</s>"];
+
+    /// Printable stand-ins for bytes, as used by byte-level BPE vocabularies.
+    fn byte_chars() -> Vec<char> {
+        let mut bytes: Vec<u32> = (b'!' as u32..=b'~' as u32).chain(0xA1..=0xAC).chain(0xAE..=0xFF).collect();
+        let mut chars = bytes.clone();
+        // Other bytes map to code points from 256 up, in order.
+        let mut next = 256;
+        for b in 0..256 {
+            if !bytes.contains(&b) {
+                bytes.push(b);
+                chars.push(next);
+                next += 1;
+            }
+        }
+        let mut ret = vec![' '; 256];
+        for (b, c) in bytes.iter().zip(chars.iter()) {
+            ret[*b as usize] = char::from_u32(*c).unwrap();
+        }
+        ret
+    }
+
+    /// Write a random one-layer llama GGUF, with a byte-level `tokenizer.json` next to it, once per test run. Returns the GGUF's path.
+    fn tiny_model() -> &'static str {
+        static PATH: OnceLock<String> = OnceLock::new();
+        PATH.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("vocllm-test-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut words: Vec<String> = SPECIALS.iter().map(|s| s.to_string()).collect();
+            words.extend(byte_chars().into_iter().map(String::from));
+
+            // Weights are drawn from a seeded generator, so every run (and host) tests the same model.
+            let mut rng = ChaCha8Rng::seed_from_u64(0);
+            let (vocab, d, ff) = (words.len(), 8, 16);
+            let mut random = |shape: &[usize]| {
+                let values: Vec<f32> = (0..shape.iter().product()).map(|_| rng.gen_range(-1.0..1.0)).collect();
+                QTensor::quantize(&Tensor::from_vec(values, shape, &Device::Cpu).unwrap(), GgmlDType::F32).unwrap()
+            };
+            let mut tensors = vec![
+                ("token_embd.weight".to_owned(), random(&[vocab, d])),
+                ("output.weight".to_owned(), random(&[vocab, d]))
+            ];
+            for name in ["attn_q", "attn_k", "attn_v", "attn_output"] {
+                tensors.push((format!("blk.0.{name}.weight"), random(&[d, d])));
+            }
+            tensors.push(("blk.0.ffn_gate.weight".to_owned(), random(&[ff, d])));
+            tensors.push(("blk.0.ffn_up.weight".to_owned(), random(&[ff, d])));
+            tensors.push(("blk.0.ffn_down.weight".to_owned(), random(&[d, ff])));
+            for name in ["output_norm.weight", "blk.0.attn_norm.weight", "blk.0.ffn_norm.weight"] {
+                let ones = Tensor::ones(d, candle_core::DType::F32, &Device::Cpu).unwrap();
+                tensors.push((name.to_owned(), QTensor::quantize(&ones, GgmlDType::F32).unwrap()));
+            }
+            let metadata = [
+                ("general.architecture", Value::String("llama".to_owned())),
+                ("llama.context_length", Value::U32(512)),
+                ("llama.attention.head_count", Value::U32(2)),
+                ("llama.attention.head_count_kv", Value::U32(2)),
+                ("llama.block_count", Value::U32(1)),
+                ("llama.embedding_length", Value::U32(d as u32)),
+                ("llama.rope.dimension_count", Value::U32(4)),
+                ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+                ("tokenizer.ggml.unknown_token_id", Value::U32(0)),
+                ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+                ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+                ("tokenizer.ggml.tokens", Value::Array(words.iter().map(|w| Value::String(w.clone())).collect()))
+            ];
+            let path = dir.join("model.gguf");
+            let metadata: Vec<(&str, &Value)> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+            let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+            gguf_file::write(&mut File::create(&path).unwrap(), &metadata, &tensors).unwrap();
+
+            let vocab: serde_json::Map<String, serde_json::Value> = words.iter().enumerate().map(|(i, w)| (w.clone(), i.into())).collect();
+            let added: Vec<_> = SPECIALS.iter().enumerate().map(|(i, s)| serde_json::json!({
+                "id": i, "content": s, "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true
+            })).collect();
+            let byte_level = serde_json::json!({ "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true });
+            let tokenizer = serde_json::json!({
+                "version": "1.0", "truncation": null, "padding": null, "added_tokens": added, "normalizer": null,
+                "pre_tokenizer": byte_level, "decoder": byte_level,
+                "post_processor": {
+                    "type": "TemplateProcessing",
+                    "single": [{ "SpecialToken": { "id": "<s>", "type_id": 0 } }, { "Sequence": { "id": "A", "type_id": 0 } }],
+                    "pair": [{ "Sequence": { "id": "A", "type_id": 0 } }],
+                    "special_tokens": { "<s>": { "id": "<s>", "ids": [1], "tokens": ["<s>"] } }
+                },
+                "model": {
+                    "type": "BPE", "dropout": null, "unk_token": "<unk>", "continuing_subword_prefix": null, "end_of_word_suffix": null,
+                    "fuse_unk": false, "byte_fallback": false, "vocab": vocab, "merges": []
+                }
+            });
+            fs::write(dir.join("tokenizer.json"), tokenizer.to_string()).unwrap();
+            path.to_string_lossy().into_owned()
+        })
+    }
+
+    /// A generator for the tiny model on CPU, with the chat options `options`.
+    fn generator(options: &[&str]) -> QuantizedTextGenerator {
+        let base = ["vocllm", "-q", "-c", "-B", "llama", "-t", "i-messenger", "-m", tiny_model()];
+        let mut args = CliArgs::try_parse_from(base.iter().chain(options).chain(&["single", "unused"])).unwrap();
+        args.fix_options().unwrap();
+        QuantizedTextGenerator::from_args(&args)
+    }
+
+    #[test]
+    fn empty_prompt_is_rejected() {
+        let mut g = generator(&["--max-tokens", "4"]);
+        assert!(matches!(g.invoke(""), Err(GenError::EmptyPrompt)));
+        assert!(matches!(g.invoke(" \n\t"), Err(GenError::EmptyPrompt)));
+        assert!(g.invoke("hi").is_ok());
+    }
+
+    #[test]
+    fn same_seed_generates_the_same_tokens_in_every_run() {
+        let options = ["--rng", "chacha8", "--seed", "42", "--temperature", "0.9", "--max-tokens", "24"];
+        let run = || {
+            let mut g = generator(&options);
+            let c = g.invoke("Tell me a story.").unwrap();
+            (c.text, g.context)
+        };
+        let first = run();
+        assert_eq!(first, run());
+    }
+
+    #[test]
+    fn prompt_after_near_full_restored_context_is_kept() {
+        let mut g = generator(&["--n-ctx", "64", "--max-tokens", "4"]);
+        let restored: Vec<u32> = (0..63).map(|i| 3 + i % 200).collect();
+        let prompt = g.tokenizer().encode("hello there", false).unwrap();
+        g.resume_from = Some(restored);
+        let c = g.invoke("hello there").unwrap();
+        // The start of the restored context is dropped to make room, leaving the prompt whole at the end.
+        assert_eq!(c.prompt_tokens, 63);
+        assert!(g.context[..c.prompt_tokens].ends_with(&prompt));
+    }
+
+    /// Decodes by joining token texts, then composes `e` and a combining acute accent into `é`, as a normalizing decoder might.
+    struct ComposingTokenizer(Vec<&'static str>);
+
+    impl TextTokenizer for ComposingTokenizer {
+        fn encode(&self, _text: &str, _add_special_tokens: bool) -> Result<Vec<u32>, TokenizerError> {
+            Err("encode is not supported by this fake, which only decodes".into())
+        }
+
+        fn decode(&self, ids: &[u32], _skip_special_tokens: bool) -> Result<String, TokenizerError> {
+            Ok(ids.iter().map(|i| self.0[*i as usize]).collect::<String>().replace("e\u{301}", "é"))
+        }
+
+        fn vocab_size(&self) -> usize {
+            self.0.len()
+        }
+
+        fn token_to_id(&self, token: &str) -> Option<u32> {
+            self.0.iter().position(|t| *t == token).map(|i| i as u32)
+        }
+
+        fn id_to_token(&self, id: u32) -> Option<String> {
+            self.0.get(id as usize).map(|t| t.to_string())
+        }
+    }
+
+    #[test]
+    fn generated_text_survives_decode_mismatch() {
+        let tokenizer = ComposingTokenizer(vec![" caf", "e", "\u{301}", " noir"]);
+        // Plain case: the decoded prompt is a prefix of the decoded context.
+        assert_eq!(decode_generated(&tokenizer, &[0, 1, 3], 2, true).unwrap(), " noir");
+        // The prompt decodes to " cafe", but the context to " café noir", where byte 5 falls inside `é`. Slicing the context
+        // at the length of the decoded prompt panicked; the generated tokens are decoded alone instead.
+        assert_eq!(decode_generated(&tokenizer, &[0, 1, 2, 3], 2, true).unwrap(), "\u{301} noir");
+    }
+
+    #[test]
+    fn device_spec_parses() {
+        assert_eq!("cpu".parse::<DeviceSpec>(), Ok(DeviceSpec::Cpu));
+        assert_eq!("Auto".parse::<DeviceSpec>(), Ok(DeviceSpec::Auto));
+        assert_eq!("cuda".parse::<DeviceSpec>(), Ok(DeviceSpec::Cuda(0)));
+        assert_eq!("metal:2".parse::<DeviceSpec>(), Ok(DeviceSpec::Metal(2)));
+        assert!("cpu:1".parse::<DeviceSpec>().is_err());
+        assert!("cuda:x".parse::<DeviceSpec>().is_err());
+        assert!("tpu".parse::<DeviceSpec>().is_err());
+    }
+
+    #[test]
+    fn device_spec_falls_back_to_cpu_only_when_allowed() {
+        let (cuda, metal) = (candle_core::utils::cuda_is_available(), candle_core::utils::metal_is_available());
+        assert!(DeviceSpec::Cpu.resolve(false).is_ok_and(|d| d.is_cpu()));
+        assert!(DeviceSpec::Cpu.resolve(true).is_err());
+        // Auto resolves to some device whatever is compiled in, or whether a GPU is present.
+        assert!(DeviceSpec::Auto.resolve(false).is_ok());
+        if !cuda && !metal {
+            assert!(DeviceSpec::Auto.resolve(false).is_ok_and(|d| d.is_cpu()));
+            assert!(DeviceSpec::Auto.resolve(true).is_err());
+        }
+        // An explicitly requested backend is never replaced by CPU.
+        if !cuda {
+            assert!(DeviceSpec::Cuda(0).resolve(false).is_err());
+        }
+        if !metal {
+            assert!(DeviceSpec::Metal(0).resolve(false).is_err());
+        }
+    }
+}
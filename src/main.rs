@@ -1,16 +1,1076 @@
+use candle_core::quantized::gguf_file::Value;
 use candle_core::utils as candle_utils;
-use log::{debug, info, trace};
-use clap::Parser;
-use argsc::CliArgs;
+use log::{debug, error, info, trace, warn};
+use clap::builder::PossibleValue;
+use clap::{CommandFactory, ValueEnum};
+use console::Style;
+use serde::Deserialize;
+use vocllm::{argsc, chat, llmcall};
+use vocllm::argsc::{BatchArg, BenchArg, CliArgs, CompactArg, ConvertArg, EmbedArg, FimArg, FitArg, InfoTopic, OutputEncoding, ReplayArg, ReportFormat, StreamFormat, StreamGranularity, SupportedBaseModels};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::llmcall::QuantizedTextGenerator;
+use vocllm::chat::{ChatHistory, ChatRole, ChatTemplate, PromptFormat, ThinkingFilter};
+use vocllm::llmcall::{Completion, FinishReason, GenError, QuantizedTextGenerator, ReplayEntry, SamplingParams, TextGenerator};
+use vocllm::profile;
+use vocllm::source;
+use vocllm::tokenizer::TextTokenizer;
+use vocllm::tts::{self, TtsProvider};
 
-mod argsc;
-mod llmcall;
-mod chat;
+/// Set by the first Ctrl-C, which cancels the ongoing generation.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::Relaxed) {
+        // Second Ctrl-C: exit immediately. Only async-signal-safe calls are allowed here.
+        unsafe { libc::_exit(130) };
+    }
+    // Some platforms reset the handler once it has run.
+    install_interrupt_handler();
+}
+
+/// Handle Ctrl-C by cancelling the ongoing generation, so that partial output is still printed and saved. A second Ctrl-C exits.
+fn install_interrupt_handler() {
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+/// A destination for generated text, such as the terminal or a file.
+trait OutputSink {
+    /// Receive the next chunk of visible text.
+    fn push(&mut self, text: &str);
+    /// Called once a completion has ended.
+    fn finish(&mut self);
+}
+
+/// Buffers streamed text, and prints it at the boundaries of the configured granularity.
+struct StreamBuffer {
+    granularity: StreamGranularity,
+    format: StreamFormat,
+    encoding: OutputEncoding,
+    /// Printed between chunks of raw text.
+    separator: String,
+    /// Whether a chunk of the current completion has been printed, so the next one is preceded by the separator.
+    started: bool,
+    /// Style of raw text, such as the REPL's color for replies.
+    style: Option<Style>,
+    /// When set, stdout is flushed at most this often while streaming, instead of after every chunk.
+    flush_interval: Option<Duration>,
+    last_flush: Cell<Instant>,
+    buf: String
+}
+
+impl StreamBuffer {
+    fn new(granularity: StreamGranularity, format: StreamFormat, encoding: OutputEncoding, separator: String, style: Option<Style>, flush_interval: Option<Duration>) -> Self {
+        Self { granularity, format, encoding, separator, started: false, style, flush_interval, last_flush: Cell::new(Instant::now()), buf: String::new() }
+    }
+
+    fn print(&self, text: &str) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(&self.encoding.encode(text));
+        if self.flush_interval.is_none_or(|i| self.last_flush.get().elapsed() >= i) {
+            let _ = stdout.flush();
+            self.last_flush.set(Instant::now());
+        }
+    }
+
+    fn print_chunk(&mut self, text: &str) {
+        let styled = match &self.style {
+            Some(style) if !text.is_empty() => Cow::Owned(style.apply_to(text).to_string()),
+            _ => Cow::Borrowed(text)
+        };
+        match self.format {
+            StreamFormat::Raw if text.is_empty() || self.separator.is_empty() => self.print(&styled),
+            StreamFormat::Raw => {
+                if self.started {
+                    self.print(&self.separator);
+                }
+                self.started = true;
+                self.print(&styled);
+            },
+            StreamFormat::Sse if text.is_empty() => {},
+            StreamFormat::Sse => self.print(&format!("data: {}\n\n", serde_json::json!({ "text": text })))
+        }
+    }
+}
+
+impl OutputSink for StreamBuffer {
+    fn push(&mut self, text: &str) {
+        self.buf.push_str(text);
+        let boundary = match self.granularity {
+            StreamGranularity::Token => Some(self.buf.len()),
+            StreamGranularity::Word => self.buf.char_indices().rev()
+                .find(|(_, c)| c.is_whitespace())
+                .map(|(i, c)| i + c.len_utf8()),
+            StreamGranularity::Line => self.buf.rfind('\n').map(|i| i + 1)
+        };
+        if let Some(n) = boundary {
+            let chunk: String = self.buf.drain(..n).collect();
+            self.print_chunk(&chunk);
+        }
+    }
+
+    /// Print any remaining text, ending the output with a newline, or with the final event.
+    fn finish(&mut self) {
+        let rest = std::mem::take(&mut self.buf);
+        self.print_chunk(&rest);
+        match self.format {
+            StreamFormat::Raw => self.print("\n"),
+            StreamFormat::Sse => self.print("data: [DONE]\n\n")
+        }
+        if self.flush_interval.is_some() {
+            let _ = std::io::stdout().flush();
+        }
+        self.started = false;
+    }
+}
+
+/// Writes generated text to a file, ending every completion with a newline.
+/// After a write fails, the error is logged once and the sink stops writing.
+struct FileSink {
+    path: String,
+    out: Option<BufWriter<File>>
+}
+
+impl FileSink {
+    fn create(path: &str) -> Self {
+        let out = File::create(path).map(BufWriter::new).unwrap_or_else(|e| {
+            error!("Failed to create output file {}", path);
+            panic!("{e:?}");
+        });
+        Self { path: path.to_owned(), out: Some(out) }
+    }
+
+    fn write_with(&mut self, f: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>) {
+        if let Some(out) = self.out.as_mut() {
+            if let Err(e) = f(out) {
+                error!("Failed to write to output file {}, cause: \"{}\"; no further output will be written to it", self.path, e);
+                self.out = None;
+            }
+        }
+    }
+}
+
+impl OutputSink for FileSink {
+    fn push(&mut self, text: &str) {
+        self.write_with(|out| out.write_all(text.as_bytes()));
+    }
+
+    fn finish(&mut self) {
+        self.write_with(|out| writeln!(out).and_then(|_| out.flush()));
+    }
+}
+
+/// Collects each completion, and speaks it once it has ended.
+struct TtsSink {
+    provider: Box<dyn TtsProvider>,
+    buf: String
+}
+
+impl OutputSink for TtsSink {
+    fn push(&mut self, text: &str) {
+        self.buf.push_str(text);
+    }
+
+    fn finish(&mut self) {
+        let text = self.buf.trim();
+        if !text.is_empty() {
+            if let Err(e) = self.provider.speak(text) {
+                error!("Speech synthesis failed, cause: \"{}\"", e);
+            }
+        }
+        self.buf.clear();
+    }
+}
+
+/// Set the console output code page, so that the console decodes printed text with the same encoding it was written in.
+#[cfg(windows)]
+fn set_console_encoding(encoding: OutputEncoding) {
+    extern "system" {
+        fn SetConsoleOutputCP(code_page: u32) -> i32;
+    }
+    // SetConsoleOutputCP takes a plain integer, and fails harmlessly (returning 0) without a console.
+    if unsafe { SetConsoleOutputCP(encoding.code_page()) } == 0 {
+        warn!("Failed to set console output code page to {}", encoding.code_page());
+    }
+}
+
+#[cfg(not(windows))]
+fn set_console_encoding(_: OutputEncoding) {}
+
+/// Dispatches each chunk of generated text to every active output sink.
+struct FanOut {
+    sinks: Vec<Box<dyn OutputSink>>
+}
+
+impl FanOut {
+    fn from_args(args: &CliArgs) -> Self {
+        Self::styled(args, None)
+    }
+
+    /// As `from_args`, printing raw text to the terminal in `style`.
+    fn styled(args: &CliArgs, style: Option<Style>) -> Self {
+        let mut out = Self::without_stdout(args);
+        out.sinks.insert(0, Box::new(StreamBuffer::new(
+            args.stream_granularity,
+            args.stream_format,
+            args.output_encoding.unwrap_or(OutputEncoding::Utf8),
+            args.chunk_separator.clone(),
+            style,
+            args.flush_interval.map(Duration::from_millis)
+        )));
+        out
+    }
+
+    /// As `from_args`, leaving stdout to the caller.
+    fn without_stdout(args: &CliArgs) -> Self {
+        let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+        if let Some(path) = &args.output_file {
+            sinks.push(Box::new(FileSink::create(path)));
+        }
+        if let Some(opt) = &args.ttsopt {
+            let provider = tts::from_option(opt).expect("tts option is validated by fix_options");
+            sinks.push(Box::new(TtsSink { provider, buf: String::new() }));
+        }
+        Self { sinks }
+    }
+}
+
+impl OutputSink for FanOut {
+    fn push(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.sinks.iter_mut().for_each(|s| s.push(text));
+        }
+    }
+
+    fn finish(&mut self) {
+        self.sinks.iter_mut().for_each(|s| s.finish());
+    }
+}
+
+fn print_stats(c: &Completion) {
+    eprintln!("finish reason: {}", c.finish_reason);
+    eprintln!("prompt tokens: {}, generated tokens: {}", c.prompt_tokens, c.generated_tokens);
+    eprintln!("generation time: {:.2}s [{:.2}t/s]", c.elapsed.as_secs_f64(), c.tokens_per_sec());
+    eprintln!("peak memory: {}", profile::format_mib(profile::peak_rss()));
+}
+
+/// Exit with a nonzero status if `tps`, the decode throughput of `what`, is below `--min-tps`.
+fn check_min_tps(args: &CliArgs, what: &str, tps: f64) {
+    let Some(min) = args.min_tps else { return };
+    if tps == 0.0 {
+        error!("Throughput of {} was not measured (is it cached? try --no-cache), failing --min-tps {:.2}", what, min);
+        std::process::exit(1);
+    }
+    if tps < min {
+        error!("Throughput of {} was {:.2}t/s, below --min-tps {:.2}", what, tps, min);
+        std::process::exit(1);
+    }
+}
+
+/// Print the time to the first token, and percentiles of the latency between later tokens, which show stalls an average hides.
+fn print_token_timings(c: &Completion) {
+    let Some((first, rest)) = c.token_latencies.split_first() else {
+        eprintln!("token timings: none recorded (cached completion)");
+        return;
+    };
+    let ms = |d: Duration| d.as_secs_f64() * 1e3;
+    eprintln!("time to first token: {:.1}ms", ms(*first));
+    if rest.is_empty() {
+        return;
+    }
+    let mut sorted = rest.to_vec();
+    sorted.sort_unstable();
+    let mean = rest.iter().sum::<Duration>() / rest.len() as u32;
+    eprintln!(
+        "inter-token latency over {} tokens: p50 {:.1}ms, p90 {:.1}ms, p99 {:.1}ms, max {:.1}ms, mean {:.1}ms",
+        rest.len(), ms(profile::percentile(&sorted, 50.0)), ms(profile::percentile(&sorted, 90.0)), ms(profile::percentile(&sorted, 99.0)),
+        ms(sorted[sorted.len() - 1]), ms(mean)
+    );
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::U8(v) => v.to_string(),
+        Value::I8(v) => v.to_string(),
+        Value::U16(v) => v.to_string(),
+        Value::I16(v) => v.to_string(),
+        Value::U32(v) => v.to_string(),
+        Value::I32(v) => v.to_string(),
+        Value::U64(v) => v.to_string(),
+        Value::I64(v) => v.to_string(),
+        Value::F32(v) => v.to_string(),
+        Value::F64(v) => v.to_string(),
+        Value::Bool(v) => v.to_string(),
+        Value::String(v) => format!("{:?}", v),
+        // Vocabularies and merges have tens of thousands of entries.
+        Value::Array(items) if items.len() > 16 => format!("[{} values]", items.len()),
+        Value::Array(items) => format!("[{}]", items.iter().map(format_value).collect::<Vec<_>>().join(", "))
+    }
+}
+
+/// Print the supported chat templates or base models, with their descriptions.
+fn print_info(topic: InfoTopic) {
+    let describe = |v: Option<PossibleValue>| {
+        if let Some(v) = v {
+            println!("{}", v.get_name());
+            if let Some(help) = v.get_help() {
+                println!("    {}", help);
+            }
+        }
+    };
+    match topic {
+        InfoTopic::Templates => {
+            for t in ChatTemplate::value_variants() {
+                describe(t.to_possible_value());
+                let tokens = t.special_tokens();
+                println!("    special tokens: {}", if tokens.is_empty() { "none".to_owned() } else { tokens.join(", ") });
+                let p = t.recommended_params();
+                let mut params = Vec::new();
+                params.extend(p.temperature.map(|t| format!("temperature {}", t)));
+                params.extend(p.repeat_penalty.map(|r| format!("repeat penalty {}", r)));
+                params.extend((!p.stop.is_empty()).then(|| format!("stop {:?}", p.stop)));
+                if !params.is_empty() {
+                    println!("    recommended: {}", params.join(", "));
+                }
+            }
+            println!("Custom templates can be read from a file with --prompt-template-file.");
+        },
+        InfoTopic::Models => SupportedBaseModels::value_variants().iter().for_each(|m| describe(m.to_possible_value()))
+    }
+}
+
+/// Print a summary and all metadata of a GGUF file, without loading its weights.
+fn inspect_model(path: &str) {
+    let (_, content, info) = llmcall::read_gguf_metadata(path).unwrap_or_else(|e| {
+        error!("Failed to read GGUF file {}, cause: \"{}\"", path, e);
+        std::process::exit(1);
+    });
+    let or_unknown = |v: Option<String>| v.unwrap_or_else(|| "unknown".to_owned());
+    println!("architecture: {}", or_unknown(info.architecture.clone()));
+    println!("context length: {}", or_unknown(info.context_length.map(|n| n.to_string())));
+    println!("tensors: {} [{} bytes]", info.n_tensors, info.total_size_in_bytes);
+    println!("eos token: {}, bos token: {}",
+        or_unknown(info.eos_token_id.map(|n| n.to_string())),
+        or_unknown(info.bos_token_id.map(|n| n.to_string()))
+    );
+    println!("metadata:");
+    let mut keys: Vec<&String> = content.metadata.keys().collect();
+    keys.sort();
+    for key in keys {
+        println!("  {} = {}", key, format_value(&content.metadata[key]));
+    }
+}
+
+/// Read the additional context file (or URL), if any, truncated to the context token budget.
+fn load_context(tokenizer: &dyn TextTokenizer, args: &CliArgs) -> Option<String> {
+    let path = args.context_file.as_ref().or(args.context_url.as_ref())?;
+    let text = source::read_to_string(path).unwrap_or_else(|e| {
+        error!("Failed to read context {}", path);
+        panic!("{e:?}");
+    });
+    let Some(max_tokens) = args.context_tokens else {
+        return Some(text);
+    };
+    let truncated = chat::truncate_to_tokens(&text, tokenizer, max_tokens).unwrap_or_else(|e| {
+        error!("Failed to tokenize context file {}", path);
+        panic!("{e:?}");
+    });
+    Some(truncated.into_owned())
+}
+
+/// Prepare user input for a prompt: strip emphasis markup, if accepted.
+fn user_text<'a>(args: &CliArgs, text: &'a str) -> Cow<'a, str> {
+    if !args.emphasis {
+        return Cow::Borrowed(text);
+    }
+    let (plain, spans) = chat::strip_emphasis(text);
+    if !spans.is_empty() {
+        debug!("Stripped emphasis from prompt, weights are not applied: {:?}", spans);
+    }
+    Cow::Owned(plain)
+}
+
+/// Print the rendered prompt to stderr, when asked to with `--print-prompt`.
+fn print_prompt(args: &CliArgs, prompt: &str) {
+    if args.print_prompt {
+        eprintln!("{}", prompt);
+    }
+}
+
+/// Generate a completion for a formatted prompt, passing it to the output sinks as configured.
+/// Returns the completion, along with the text that was actually shown (i.e. without reasoning blocks, if those are stripped).
+fn respond(g: &mut impl TextGenerator, args: &CliArgs, out: &mut FanOut, prompt: &str) -> Result<(Completion, String), GenError> {
+    print_prompt(args, prompt);
+    show(args, out, |on_text| g.complete(prompt, &INTERRUPTED, on_text))
+}
+
+/// Run `generate`, passing the generated text to the output sinks as it is produced (or once it is complete, without streaming),
+/// and then finish the output. `generate` is given a callback for streamed text, if streaming.
+fn show<F>(args: &CliArgs, out: &mut FanOut, generate: F) -> Result<(Completion, String), GenError>
+where F: FnOnce(Option<&mut dyn FnMut(&str)>) -> Result<Completion, GenError> {
+    let mut thinking = args.strip_thinking.then(|| ThinkingFilter::new(&args.think_open, &args.think_close));
+    INTERRUPTED.store(false, Ordering::Relaxed);
+    // Post-processing needs the whole completion, so nothing is streamed.
+    if args.no_stream || args.postprocess_cmd.is_some() {
+        let r = generate(None)?;
+        let mut shown = match thinking.as_mut() {
+            Some(f) => f.push(&r.text) + &f.finish(),
+            None => r.text.clone()
+        };
+        if let Some(cmd) = &args.postprocess_cmd {
+            shown = postprocess(cmd, shown);
+        }
+        out.push(&shown);
+        out.finish();
+        Ok((r, shown))
+    } else {
+        let mut shown = String::new();
+        let r = generate(Some(&mut |t: &str| {
+            let visible = match thinking.as_mut() {
+                Some(f) => f.push(t),
+                None => t.to_owned()
+            };
+            out.push(&visible);
+            shown.push_str(&visible);
+        }));
+        if let Some(f) = thinking.as_mut() {
+            let rest = f.finish();
+            out.push(&rest);
+            shown.push_str(&rest);
+        }
+        out.finish();
+        r.map(|r| (r, shown))
+    }
+}
+
+/// Pipe `text` through the `--postprocess-cmd` command, returning what it prints, or `text` itself (with a warning) if the command fails.
+/// The command line is split on whitespace, without any shell quoting.
+fn postprocess(command_line: &str, text: String) -> String {
+    let mut parts = command_line.split_whitespace();
+    let program = parts.next().expect("postprocess-cmd is validated by fix_options");
+    let run = || -> std::io::Result<String> {
+        let mut child = Command::new(program).args(parts).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        let mut stdin = child.stdin.take().expect("Child stdin is piped");
+        let input = text.as_bytes();
+        // Written from another thread, so that a command printing a lot before reading all its input cannot deadlock.
+        // Moving stdin into the thread closes it once written, so the command sees the end of its input.
+        let writer = std::thread::scope(|s| {
+            let writer = s.spawn(move || stdin.write_all(input));
+            let output = child.wait_with_output();
+            (writer.join().expect("Stdin writer panicked"), output)
+        });
+        let output = match writer {
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+            (Ok(()), Ok(output)) => output
+        };
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!("exited with {}", output.status)));
+        }
+        let mut processed = String::from_utf8_lossy(&output.stdout).into_owned();
+        // Most commands end their output with a newline, and the output sinks add one of their own.
+        if processed.ends_with('\n') {
+            processed.pop();
+            if processed.ends_with('\r') {
+                processed.pop();
+            }
+        }
+        Ok(processed)
+    };
+    run().unwrap_or_else(|e| {
+        warn!("Post-processing command \"{}\" failed, cause: \"{}\"; using the unprocessed output", command_line, e);
+        text
+    })
+}
+
+/// Continue generation past each stop sequence, treating stops as breaks between sections, until another reason ends generation
+/// or `max_tokens` tokens have been generated in all. Each section is output as a completion of its own.
+/// Returns the last completion, with token counts and time summed over all sections, and appends the text shown of each section to `shown`.
+fn continue_after_stop(g: &mut QuantizedTextGenerator, args: &CliArgs, out: &mut FanOut, first: Completion, shown: &mut String) -> Completion {
+    let max_tokens = args.max_tokens.expect("continue_after_stop requires max_tokens");
+    let mut r = first;
+    let (mut generated, mut elapsed) = (r.generated_tokens, r.elapsed);
+    let mut latencies = std::mem::take(&mut r.token_latencies);
+    while r.finish_reason == FinishReason::Stop && generated < max_tokens {
+        debug!("Continuing past stop sequence, after {} of {} tokens", generated, max_tokens);
+        let params = SamplingParams { max_tokens: Some(max_tokens - generated), min_tokens: None, ..g.params().clone() };
+        let (next, text) = show(args, out, |on_text| g.continue_cancellable(&params, &INTERRUPTED, on_text)).unwrap_or_else(|e| {
+            error!("Generation failed, cause: \"{}\"", e);
+            std::process::exit(1);
+        });
+        shown.push_str(&text);
+        r = next;
+        generated += r.generated_tokens;
+        elapsed += r.elapsed;
+        latencies.append(&mut r.token_latencies);
+    }
+    Completion { generated_tokens: generated, elapsed, token_latencies: latencies, ..r }
+}
+
+/// Read prompts from stdin in a loop, replying to each with the chat history as context, until EOF or `/exit`.
+/// `/continue` has the model continue its last answer instead.
+fn run_repl(g: &mut QuantizedTextGenerator, args: &CliArgs, context: Option<String>) {
+    let history_path = PathBuf::from(args.historyfile.as_ref().unwrap());
+    let history = if args.disable_history {
+        ChatHistory::new(args.history_count).with_overflow_policy(args.history_overflow)
+    } else {
+        ChatHistory::new(args.history_count).with_overflow_policy(args.history_overflow).read_from(&history_path).unwrap_or_else(|e| {
+            error!("Failed to load history file {}, cause: \"{}\", starting with empty history", history_path.display(), e);
+            ChatHistory::new(args.history_count).with_overflow_policy(args.history_overflow)
+        })
+    };
+    let mut history = history.with_max_turns(args.history_max_turns);
+    if let Some(greeting) = args.character_greeting.as_deref().filter(|_| history.is_empty() && !args.disable_history) {
+        println!("{}", greeting);
+        history.record_message(ChatRole::Assistant, greeting);
+        if !args.incognito {
+            if let Err(e) = ChatHistory::append_turn_to_file(&history_path, &[(ChatRole::Assistant, greeting)]) {
+                error!("Failed to append to history file {}, cause: \"{}\"", history_path.display(), e);
+            }
+        }
+    }
+
+    // console leaves styles out by itself when stdout is not a terminal or NO_COLOR is set.
+    let (prompt_style, reply_style) = if args.color {
+        (Style::new().green().bold(), Some(Style::new().cyan()))
+    } else {
+        (Style::new(), None)
+    };
+    let mut out = FanOut::styled(args, reply_style);
+    let stdin = std::io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("{}", prompt_style.apply_to("> "));
+        let _ = std::io::stdout().flush();
+        line.clear();
+        match stdin.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {},
+            Err(e) => {
+                error!("Failed to read from stdin, cause: \"{}\"", e);
+                break;
+            }
+        }
+        let input = line.trim();
+        if input.is_empty() {
+            continue;
+        }
+        if input == "/exit" {
+            break;
+        }
+        if input == "/continue" {
+            continue_last_answer(g, args, &mut out, &mut history, &history_path, context.clone());
+            continue;
+        }
+        let input = &*user_text(args, input);
+
+        let p = if args.raw {
+            input.to_owned()
+        } else {
+            chat::make_prompt_with_history(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), input, context.clone(), &history, args.normalize_prompt)
+        };
+        let (r, shown) = match respond(g, args, &mut out, &p) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Generation failed, cause: \"{}\"", e);
+                continue;
+            }
+        };
+        if args.stats {
+            print_stats(&r);
+        }
+        if args.token_timings {
+            print_token_timings(&r);
+        }
+        if args.disable_history {
+            continue;
+        }
+        let reply = shown.trim();
+        history.record_message(ChatRole::User, input);
+        history.record_message(ChatRole::Assistant, reply);
+        if !args.incognito {
+            if let Err(e) = ChatHistory::append_turn_to_file(&history_path, &[(ChatRole::User, input), (ChatRole::Assistant, reply)]) {
+                error!("Failed to append to history file {}, cause: \"{}\"", history_path.display(), e);
+            }
+        }
+        print_token_meter(g, &history);
+    }
+}
+
+/// Print how many tokens the chat history occupies, out of the budget beyond which old messages are dropped.
+fn print_token_meter(g: &QuantizedTextGenerator, history: &ChatHistory) {
+    match history.token_count(g.tokenizer()) {
+        Ok(n) => eprintln!("[{}/{}]", n, history.token_limit()),
+        Err(e) => warn!("Failed to count history tokens, cause: \"{}\"", e)
+    }
+}
+
+/// Have the model continue its last answer (e.g. one cut off by `max_tokens`), extending it in history.
+fn continue_last_answer(g: &mut QuantizedTextGenerator, args: &CliArgs, out: &mut FanOut, history: &mut ChatHistory, history_path: &Path, context: Option<String>) {
+    if !matches!(history.last_message(), Some((ChatRole::Assistant, _))) {
+        error!("Nothing to continue: the last message in history is not an answer.");
+        return;
+    }
+    let p = chat::make_continue_prompt(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), context, history, args.normalize_prompt);
+    let (r, shown) = match respond(g, args, out, &p) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Generation failed, cause: \"{}\"", e);
+            return;
+        }
+    };
+    if args.stats {
+        print_stats(&r);
+    }
+    if args.token_timings {
+        print_token_timings(&r);
+    }
+    let continuation = shown.trim_end();
+    history.extend_last_message(continuation);
+    if !args.incognito {
+        if let Err(e) = ChatHistory::append_continuation_to_file(history_path, continuation) {
+            error!("Failed to append to history file {}, cause: \"{}\"", history_path.display(), e);
+        }
+    }
+    print_token_meter(g, history);
+}
+
+/// Print an embedding of the prompt as JSON, without loading the full model.
+fn embed(args: &CliArgs, earg: &EmbedArg) {
+    let run = || -> Result<Vec<f32>, GenError> {
+        let device = args.device_spec().resolve(args.prefer_gpu)?;
+        let tokenizer = llmcall::load_tokenizer(args.tokenizer_json.as_ref().unwrap())?;
+        llmcall::embed_text(args.model_path.as_ref().unwrap(), &tokenizer, &earg.prompt, earg.pooling, &device)
+    };
+    match run() {
+        Ok(v) => println!("{}", serde_json::json!({ "dimensions": v.len(), "embedding": v })),
+        Err(e) => {
+            error!("Failed to compute embedding, cause: \"{}\"", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Check the model and tokenizer, printing the outcome of each check. Returns true if all passed.
+/// Later checks depend on earlier ones, so they are skipped once a check fails.
+fn doctor(args: &CliArgs) -> bool {
+    let report = |name: &str, result: Result<String, String>| -> bool {
+        match &result {
+            Ok(detail) => println!("[ok]   {}: {}", name, detail),
+            Err(cause) => println!("[FAIL] {}: {}", name, cause)
+        }
+        result.is_ok()
+    };
+    let skip = |names: &[&str]| names.iter().for_each(|n| println!("[skip] {}", n));
+
+    let model_path = args.model_path.as_ref().unwrap();
+    let tokenizer_path = args.tokenizer_json.as_ref().unwrap();
+    let exists = |path: &str| if Path::new(path).is_file() { Ok(path.to_owned()) } else { Err(format!("{} is not a file", path)) };
+    let mut ok = report("model file", exists(model_path));
+    ok &= report("tokenizer file", exists(tokenizer_path).or_else(|e| {
+        // A missing tokenizer.json is fine when the tokenizer can be assembled from its component files.
+        let dir = Path::new(tokenizer_path).parent().unwrap_or(Path::new("."));
+        if dir.join("vocab.json").is_file() { Ok(format!("{} (vocab.json and merges.txt)", dir.display())) } else { Err(e) }
+    }));
+    if !ok {
+        skip(&["gguf", "tokenizer", "vocabulary", "eos token", "device", "forward pass"]);
+        return false;
+    }
+
+    let gguf = llmcall::read_gguf_metadata(model_path);
+    let tokenizer = llmcall::load_tokenizer(tokenizer_path);
+    ok &= report("gguf", gguf.as_ref()
+        .map(|(_, _, info)| format!("{} [{} tensors]", info.architecture.as_deref().unwrap_or("unknown architecture"), info.n_tensors))
+        .map_err(|e| e.to_string()));
+    ok &= report("tokenizer", tokenizer.as_ref()
+        .map(|t| format!("{} tokens", t.get_vocab_size(true)))
+        .map_err(|e| e.to_string()));
+    let (Ok((_, content, info)), Ok(tokenizer)) = (gguf, tokenizer) else {
+        skip(&["vocabulary", "eos token", "device", "forward pass"]);
+        return false;
+    };
+
+    let n_vocab = content.tensor_infos.get("token_embd.weight").map(|t| t.shape.dims()[0]);
+    let n_tokenizer = tokenizer.get_vocab_size(true);
+    ok &= report("vocabulary", match n_vocab {
+        None => Err("model has no token_embd.weight tensor".to_owned()),
+        Some(n) if n_tokenizer > n => Err(format!("tokenizer has {} tokens, but the model only has embeddings for {}", n_tokenizer, n)),
+        Some(n) => Ok(format!("{} embeddings for {} tokenizer tokens", n, n_tokenizer))
+    });
+    let eos = match &args.eos_str {
+        Some(text) => llmcall::token_id_of(&tokenizer, text).map(Some).map_err(|e| format!("--eos-str {:?} is {}", text, e)),
+        None => Ok(args.eos_token.or(info.eos_token_id))
+    };
+    ok &= report("eos token", match eos {
+        Err(e) => Err(e),
+        Ok(None) => Err("GGUF does not specify an EOS token, set --eos-token".to_owned()),
+        Ok(Some(id)) if n_vocab.is_some_and(|n| id as usize >= n) => Err(format!("EOS token {} is outside the vocabulary", id)),
+        Ok(Some(id)) => Ok(format!("{} {:?}", id, tokenizer.id_to_token(id).unwrap_or_default()))
+    });
+
+    let device = args.device_spec().resolve(args.prefer_gpu);
+    ok &= report("device", device.as_ref().map(|d| format!("{:?}", d)).map_err(|e| e.to_string()));
+    let Ok(device) = device else {
+        skip(&["forward pass"]);
+        return false;
+    };
+    let token = info.bos_token_id.unwrap_or(0);
+    ok &= report("forward pass", match llmcall::forward_one(model_path, &device, token) {
+        Ok(logits) if logits.iter().any(|l| !l.is_finite()) => Err("logits are not all finite".to_owned()),
+        Ok(logits) => Ok(format!("{} logits for token {}", logits.len(), token)),
+        Err(e) => Err(e.to_string())
+    });
+    ok
+}
+
+/// Assemble a fill-in-the-middle prompt, in prefix-suffix-middle order, with FIM tokens from the arguments or else the GGUF.
+fn fim_prompt(g: &QuantizedTextGenerator, farg: &FimArg) -> Result<String, String> {
+    let flags = [(&farg.fim_pre, "--fim-pre"), (&farg.fim_suf, "--fim-suf"), (&farg.fim_mid, "--fim-mid")];
+    let mut tokens = Vec::with_capacity(3);
+    for ((flag_value, flag), id) in flags.into_iter().zip(g.special_tokens().fim) {
+        let token = flag_value.clone()
+            .or_else(|| id.and_then(|id| g.tokenizer().id_to_token(id)))
+            .ok_or_else(|| format!("GGUF does not specify a FIM token for {}, set it explicitly", flag))?;
+        tokens.push(token);
+    }
+    debug!("FIM tokens: {:?}", tokens);
+    Ok(format!("{}{}{}{}{}", tokens[0], farg.prefix, tokens[1], farg.suffix, tokens[2]))
+}
+
+/// Replace the oldest messages in the history file by a summary, keeping the most recent ones verbatim.
+fn compact(g: &mut QuantizedTextGenerator, args: &CliArgs, carg: &CompactArg) {
+    let path = PathBuf::from(args.historyfile.as_ref().unwrap());
+    let mut history = ChatHistory::load_from(&path, usize::MAX).unwrap_or_else(|e| {
+        error!("Failed to load history file {}", path.display());
+        panic!("{e:?}");
+    });
+    let n_before = history.len();
+    let old = history.drain_oldest(carg.keep_tokens.unwrap_or(args.history_count / 2));
+    // A single message would only be replaced by its own summary.
+    if old.len() < 2 {
+        eprintln!("Nothing to compact in {} ({} messages).", path.display(), n_before);
+        return;
+    }
+
+    let p = chat::make_summary_prompt(&args.prompt_format(), &old);
+    print_prompt(args, &p);
+    let summary = g.invoke(&p).unwrap_or_else(|e| {
+        error!("Failed to summarize history, cause: \"{}\"", e);
+        std::process::exit(1);
+    });
+    let summary = summary.text.trim();
+    if summary.is_empty() {
+        error!("Model produced an empty summary, leaving history unchanged.");
+        std::process::exit(1);
+    }
+    history.prepend_message(ChatRole::System, &format!("{}{}", chat::SUMMARY_PREFIX, summary));
+    if let Err(e) = history.save_to(&path) {
+        error!("Failed to write history file {}, cause: \"{}\"", path.display(), e);
+        std::process::exit(1);
+    }
+    eprintln!("Compacted {}: {} messages summarized, {} messages now.", path.display(), old.len(), history.len());
+}
+
+fn convert_history(args: &CliArgs, carg: &ConvertArg) {
+    let path = PathBuf::from(args.historyfile.as_ref().unwrap());
+    let mut history = ChatHistory::load_from(&path, usize::MAX).unwrap_or_else(|e| {
+        error!("Failed to load history file {}", path.display());
+        panic!("{e:?}");
+    });
+    if history.is_empty() {
+        eprintln!("No history to convert in {}.", path.display());
+        return;
+    }
+    if let Some(from) = carg.from {
+        let n_before = history.len();
+        let changed = history.strip_markup(&PromptFormat { template: from, ..args.prompt_format() });
+        info!("Removed {:?} markup from {} messages, dropping {} left empty.", from, changed, n_before - history.len());
+    }
+
+    let mut text = String::new();
+    args.prompt_format().insert_history(&mut text, &history);
+    let written = match &carg.output {
+        Some(out) => fs::write(out, &text).map_err(|e| format!("failed to write {}: {}", out.display(), e)),
+        None => std::io::stdout().write_all(text.as_bytes()).map_err(|e| format!("failed to write to stdout: {}", e))
+    };
+    if let Err(e) = written {
+        error!("Failed to write converted history, cause: \"{}\"", e);
+        std::process::exit(1);
+    }
+    if carg.in_place {
+        if let Err(e) = history.save_to(&path) {
+            error!("Failed to write history file {}, cause: \"{}\"", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+    eprintln!("Rendered {} messages of {} with template {:?}.", history.len(), path.display(), args.template);
+}
+
+/// A line of batch input, with optional overrides of the sampling parameters.
+#[derive(Deserialize)]
+struct BatchLine {
+    prompt: String,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<usize>,
+    stop: Option<Vec<String>>
+}
+
+impl BatchLine {
+    fn params(&self, defaults: &SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature.unwrap_or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            min_tokens: defaults.min_tokens,
+            stop: self.stop.clone().unwrap_or_else(|| defaults.stop.clone())
+        }
+    }
+}
+
+/// Count the tokens of each part of the prompt which would be built for `farg.prompt`, and compare the total with the model's context.
+fn fit(args: &CliArgs, farg: &FitArg) {
+    let run = || -> Result<serde_json::Value, GenError> {
+        let (_, _, info) = llmcall::read_gguf_metadata(args.model_path.as_ref().unwrap())?;
+        let tokenizer = llmcall::load_tokenizer(args.tokenizer_json.as_ref().unwrap())?;
+        let count = |text: &str| tokenizer.encode(text, false).map(|e| e.len()).map_err(GenError::Tokenizer);
+
+        let context = load_context(&tokenizer, args);
+        let user_prompt = user_text(args, &farg.prompt);
+        let history = if farg.with_history && !args.disable_history {
+            ChatHistory::new(args.history_count)
+                .with_overflow_policy(args.history_overflow)
+                .read_from(Path::new(args.historyfile.as_ref().unwrap()))
+                .map_err(GenError::Io)?
+                .with_max_turns(args.history_max_turns)
+        } else {
+            ChatHistory::new(args.history_count)
+        };
+        let prompt = if args.raw {
+            user_prompt.to_string()
+        } else {
+            chat::make_prompt_with_history(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), &user_prompt, context.clone(), &history, args.normalize_prompt)
+        };
+
+        // Parts are counted separately, so their sum may differ slightly from the total, where tokens can merge across boundaries.
+        let format = args.prompt_format();
+        let mut parts = Vec::new();
+        if !args.raw {
+            parts.push(("system", count(&format.apply_one(ChatRole::System, args.sysprompt.as_ref().unwrap()))?));
+            let mut text = String::new();
+            format.insert_history(&mut text, &history);
+            parts.push(("history", count(&text)?));
+            parts.push(("context", context.as_deref().map(|c| count(&format.apply_one(ChatRole::System, c))).transpose()?.unwrap_or(0)));
+        }
+        let user = if args.raw {
+            user_prompt.to_string()
+        } else {
+            format.apply_one(ChatRole::User, &user_prompt) + &format.generation_lead()
+        };
+        parts.push(("user", count(&user)?));
+        let total = tokenizer.encode(prompt.as_str(), true).map_err(GenError::Tokenizer)?.len();
+        let window = info.usable_context(args.n_ctx);
+        let mut report = serde_json::json!({
+            "total": total,
+            "context_window": window,
+            "remaining": window.saturating_sub(total),
+            "fits": total < window && args.max_tokens.is_none_or(|n| total + n <= window)
+        });
+        for (name, n) in parts {
+            report[name] = n.into();
+        }
+        Ok(report)
+    };
+    let report = run().unwrap_or_else(|e| {
+        error!("Failed to estimate fit, cause: \"{}\"", e);
+        std::process::exit(1);
+    });
+    if farg.format == ReportFormat::Json {
+        println!("{}", report);
+        return;
+    }
+    for (name, key) in [("system", "system"), ("history", "history"), ("context", "context"), ("user", "user"), ("total", "total"), ("context window", "context_window")] {
+        if let Some(n) = report.get(key).and_then(|n| n.as_u64()) {
+            println!("{:<16}{:>8} tokens", name, n);
+        }
+    }
+    println!("{:<16}{:>8} tokens left for generation", "remaining", report["remaining"].as_u64().unwrap_or(0));
+    if report["fits"] == true {
+        println!("The prompt fits.");
+    } else {
+        println!("The prompt does not fit{}.", if args.max_tokens.is_some() { " with max_tokens" } else { "" });
+    }
+}
+
+/// Run every prompt of the batch input file, writing one JSON object per prompt to the output file.
+fn run_batch(g: &mut QuantizedTextGenerator, args: &CliArgs, barg: &BatchArg, context: Option<String>) {
+    let input = fs::read_to_string(&barg.input).unwrap_or_else(|e| {
+        error!("Failed to read batch input {}", barg.input);
+        panic!("{e:?}");
+    });
+    let mut out = BufWriter::new(File::create(&barg.output).unwrap_or_else(|e| {
+        error!("Failed to create batch output {}", barg.output);
+        panic!("{e:?}");
+    }));
+
+    // Lines which are not JSON objects with a prompt are taken as the prompt text itself.
+    let prompts: Vec<BatchLine> = input.lines().map(|line| {
+        serde_json::from_str::<BatchLine>(line).unwrap_or_else(|_| BatchLine {
+            prompt: line.to_owned(), temperature: None, top_p: None, max_tokens: None, stop: None
+        })
+    }).collect();
+
+    let (mut total_tokens, mut total_time) = (0, Duration::ZERO);
+    for (i, line) in prompts.iter().enumerate() {
+        let prompt = &user_text(args, &line.prompt).into_owned();
+        eprintln!("[{}/{}] Generating...", i + 1, prompts.len());
+        g.reset();
+        let p = if args.raw {
+            prompt.clone()
+        } else {
+            chat::make_prompt(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), prompt, context.clone(), args.normalize_prompt)
+        };
+        let params = line.params(g.params());
+        let result = if prompt.trim().is_empty() {
+            Err(GenError::EmptyPrompt)
+        } else {
+            print_prompt(args, &p);
+            g.invoke_with_params(&p, &params)
+        };
+        let entry = match result {
+            Ok(r) => {
+                total_tokens += r.generated_tokens;
+                total_time += r.elapsed;
+                let text = if args.strip_thinking {
+                    let mut f = ThinkingFilter::new(&args.think_open, &args.think_close);
+                    f.push(&r.text) + &f.finish()
+                } else {
+                    r.text
+                };
+                serde_json::json!({ "prompt": prompt, "completion": text, "finish_reason": r.finish_reason.to_string() })
+            },
+            Err(e) => {
+                error!("Generation failed for prompt {}, cause: \"{}\"", i + 1, e);
+                serde_json::json!({ "prompt": prompt, "error": e.to_string(), "error_kind": e.kind() })
+            }
+        };
+        writeln!(out, "{}", entry).and_then(|_| out.flush()).unwrap_or_else(|e| {
+            error!("Failed to write to batch output {}", barg.output);
+            panic!("{e:?}");
+        });
+    }
+
+    let secs = total_time.as_secs_f64();
+    let tps = if secs > 0.0 { total_tokens as f64 / secs } else { 0.0 };
+    eprintln!("Completed {} prompts: {} tokens in {:.2}s [{:.2}t/s]", prompts.len(), total_tokens, secs, tps);
+    check_min_tps(args, "the batch", tps);
+}
+
+/// Replay every generation in the log at `rarg.log`, printing whether each reproduces its recorded tokens.
+/// Returns whether all of them did.
+fn replay(g: &mut QuantizedTextGenerator, rarg: &ReplayArg) -> bool {
+    let text = fs::read_to_string(&rarg.log).unwrap_or_else(|e| {
+        error!("Failed to read replay log {}, cause: \"{}\"", rarg.log, e);
+        std::process::exit(1);
+    });
+    let mut all_match = true;
+    for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let entry: ReplayEntry = serde_json::from_str(line).unwrap_or_else(|e| {
+            error!("Invalid replay entry on line {}, cause: \"{}\"", i + 1, e);
+            std::process::exit(1);
+        });
+        let replayed = g.replay(&entry).unwrap_or_else(|e| {
+            error!("Replay of line {} failed, cause: \"{}\"", i + 1, e);
+            std::process::exit(1);
+        });
+        let piece = |t: Option<&u32>| t.map_or("<none>".to_owned(), |t| format!("{} {:?}", t, g.tokenizer().decode(&[*t], false).unwrap_or_default()));
+        match (0..entry.output.len().max(replayed.len())).find(|&k| entry.output.get(k) != replayed.get(k)) {
+            None => println!("line {}: {} tokens, identical", i + 1, replayed.len()),
+            Some(k) => {
+                all_match = false;
+                println!("line {}: diverged at step {}: recorded {}, replayed {}", i + 1, k, piece(entry.output.get(k)), piece(replayed.get(k)));
+            }
+        }
+    }
+    all_match
+}
+
+/// Warm up the generator, which only makes later timings more stable, so failure is not fatal.
+fn warmup(g: &mut QuantizedTextGenerator) {
+    if let Err(e) = g.warmup() {
+        warn!("Warmup failed, cause: \"{}\"", e);
+    }
+}
+
+/// Load each model in turn, warm it up, generate `barg.tokens` tokens from the same prompt, and tabulate load time and decode speed.
+/// Every generator is dropped before the next model is loaded, so only one model is in memory at a time.
+fn bench(args: &mut CliArgs, barg: &BenchArg) {
+    let mut paths = vec![args.model_path.clone().unwrap()];
+    paths.extend(barg.models.iter().cloned());
+    args.max_tokens = Some(barg.tokens);
+    // A cached completion would report no decoding time at all.
+    args.no_cache = true;
+    let prompt = if args.raw {
+        barg.prompt.clone()
+    } else {
+        chat::make_prompt(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), &barg.prompt, None, args.normalize_prompt)
+    };
+
+    let mut rows = Vec::new();
+    for path in paths {
+        info!("Benchmarking {}", path);
+        args.model_path = Some(path.clone());
+        let load_start = Instant::now();
+        let mut g = QuantizedTextGenerator::from_args(args);
+        let load = load_start.elapsed().as_secs_f64();
+        warmup(&mut g);
+        let c = g.invoke(&prompt).unwrap_or_else(|e| {
+            error!("Generation with {} failed, cause: \"{}\"", path, e);
+            std::process::exit(1);
+        });
+        drop(g);
+        rows.push(serde_json::json!({
+            "model": path,
+            "load_secs": load,
+            "prompt_tokens": c.prompt_tokens,
+            "generated_tokens": c.generated_tokens,
+            "tokens_per_sec": c.tokens_per_sec()
+        }));
+    }
+
+    if barg.format == ReportFormat::Json {
+        println!("{}", serde_json::Value::Array(rows.clone()));
+    } else {
+        print_bench_table(&rows);
+    }
+    // Checked once the whole report is out, so that a slow model does not hide the results of the others.
+    for r in &rows {
+        check_min_tps(args, r["model"].as_str().unwrap_or(""), r["tokens_per_sec"].as_f64().unwrap_or(0.0));
+    }
+}
+
+fn print_bench_table(rows: &[serde_json::Value]) {
+    let width = rows.iter().filter_map(|r| r["model"].as_str()).map(|m| m.len()).max().unwrap_or(0).max("model".len());
+    println!("{:<width$}  {:>8}  {:>8}  {:>9}  {:>8}", "model", "load (s)", "prompt", "generated", "t/s");
+    for r in rows {
+        println!("{:<width$}  {:>8.2}  {:>8}  {:>9}  {:>8.2}",
+            r["model"].as_str().unwrap_or(""),
+            r["load_secs"].as_f64().unwrap_or(0.0),
+            r["prompt_tokens"].as_u64().unwrap_or(0),
+            r["generated_tokens"].as_u64().unwrap_or(0),
+            r["tokens_per_sec"].as_f64().unwrap_or(0.0)
+        );
+    }
+}
 
 fn main() {
-    let mut args = CliArgs::parse();
+    let mut args = CliArgs::parse_args();
     if std::env::var("RUST_LOG").is_err() {
         if args.verbose {
             std::env::set_var("RUST_LOG", "trace");
@@ -27,9 +1087,46 @@ fn main() {
         candle_utils::with_f16c()
     );
     debug!("Received {:#?}", args);
-    args.fix_options();
+    if let Some(encoding) = args.output_encoding {
+        set_console_encoding(encoding);
+    }
+    if let argsc::Commands::Info(iarg) = &args.command {
+        print_info(iarg.topic);
+        return;
+    }
+    if let Err(e) = args.fix_options() {
+        CliArgs::command().error(clap::error::ErrorKind::ValueValidation, e).exit();
+    }
+
+    if let argsc::Commands::Inspect = args.command {
+        inspect_model(args.model_path.as_ref().unwrap());
+        return;
+    }
+    if let argsc::Commands::Doctor = args.command {
+        if !doctor(&args) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let argsc::Commands::Fit(farg) = &args.command {
+        fit(&args, farg);
+        return;
+    }
+    if let argsc::Commands::ConvertHistory(carg) = &args.command {
+        convert_history(&args, carg);
+        return;
+    }
+    if let argsc::Commands::Bench(barg) = &args.command {
+        let barg = barg.clone();
+        bench(&mut args, &barg);
+        return;
+    }
+    if let argsc::Commands::Embed(earg) = &args.command {
+        embed(&args, earg);
+        return;
+    }
 
-    let mut g = match args.base_model {
+    let mut g = match args.base_model.as_ref().unwrap() {
         argsc::SupportedBaseModels::Mistral | argsc::SupportedBaseModels::Llama => {
             //Conveniently candle supports all llama architecture ggufs under the same model.
             QuantizedTextGenerator::from_args(&args)
@@ -37,17 +1134,185 @@ fn main() {
         argsc::SupportedBaseModels::Rwkv => todo!("Will implement once support for Llama-based GGUFs is complete."),
     };
 
-    match args.command {
-        argsc::Commands::Ripl => todo!("Will implement after line streaming."),
+    if let Some(path) = &args.load_state {
+        g.load_state_infallible(path);
+    }
+    if args.warmup {
+        warmup(&mut g);
+    }
+
+    let context = load_context(g.tokenizer(), &args);
+    install_interrupt_handler();
+
+    match &args.command {
+        argsc::Commands::Ripl => run_repl(&mut g, &args, context),
         argsc::Commands::Single(parg) => {
-            if args.no_stream {
-                trace!("Building prompt...");
-                let p = chat::make_prompt(args.template, args.sysprompt.as_ref().unwrap(), &parg.prompt, None);
-                let r = g.invoke_infallible(&p);
-                println!("{}", r);
+            if parg.prompt.trim().is_empty() {
+                error!("Prompt is empty, nothing to generate.");
+                std::process::exit(1);
+            }
+            trace!("Building prompt...");
+            let prompt = user_text(&args, &parg.prompt);
+            let p = if args.raw {
+                prompt.into_owned()
+            } else if args.load_state.is_some() {
+                chat::make_followup_prompt(&args.prompt_format(), &prompt, context, args.normalize_prompt)
+            } else if !parg.turns.is_empty() {
+                // Every turn is kept, however long, since they were given explicitly.
+                let mut history = ChatHistory::new(usize::MAX);
+                for (role, text) in &parg.turns {
+                    let text = if *role == ChatRole::User { user_text(&args, text) } else { Cow::Borrowed(text.as_str()) };
+                    history.record_message(*role, &text);
+                }
+                chat::make_prompt_with_history(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), &prompt, context, &history, args.normalize_prompt)
             } else {
-                todo!("Implement line-streaming.")
+                chat::make_prompt(&args.prompt_format(), args.sysprompt.as_ref().unwrap(), &prompt, context, args.normalize_prompt)
+            };
+            let json = parg.format == ReportFormat::Json;
+            let mut out = if json { FanOut::without_stdout(&args) } else { FanOut::from_args(&args) };
+            let (mut r, mut shown) = respond(&mut g, &args, &mut out, &p).unwrap_or_else(|e| {
+                error!("Generation failed, cause: \"{}\"", e);
+                std::process::exit(1);
+            });
+            if args.continue_after_stop {
+                r = continue_after_stop(&mut g, &args, &mut out, r, &mut shown);
+            }
+            if json {
+                println!("{}", serde_json::json!({
+                    "text": shown,
+                    "finish_reason": r.finish_reason.to_string(),
+                    "prompt_tokens": r.prompt_tokens,
+                    "generated_tokens": r.generated_tokens
+                }));
+            }
+            if args.stats {
+                print_stats(&r);
+            }
+            if args.token_timings {
+                print_token_timings(&r);
+            }
+            if let Some(path) = &args.save_state {
+                g.save_state(path);
+            }
+            check_min_tps(&args, "the completion", r.tokens_per_sec());
+        },
+        argsc::Commands::Batch(barg) => run_batch(&mut g, &args, barg, context),
+        argsc::Commands::Replay(rarg) => {
+            if !replay(&mut g, rarg) {
+                std::process::exit(1);
+            }
+        },
+        argsc::Commands::Compact(carg) => compact(&mut g, &args, carg),
+        argsc::Commands::Fim(farg) => {
+            let p = fim_prompt(&g, farg).unwrap_or_else(|e| {
+                error!("Cannot build FIM prompt: {}", e);
+                std::process::exit(1);
+            });
+            let (r, _) = respond(&mut g, &args, &mut FanOut::from_args(&args), &p).unwrap_or_else(|e| {
+                error!("Generation failed, cause: \"{}\"", e);
+                std::process::exit(1);
+            });
+            if args.stats {
+                print_stats(&r);
+            }
+            if args.token_timings {
+                print_token_timings(&r);
+            }
+            check_min_tps(&args, "the completion", r.tokens_per_sec());
+        },
+        argsc::Commands::Inspect | argsc::Commands::Embed(_) | argsc::Commands::Info(_) | argsc::Commands::Doctor | argsc::Commands::Fit(_) | argsc::Commands::Bench(_) | argsc::Commands::ConvertHistory(_) => {
+            unreachable!("Inspect, Embed, Info, Doctor, Fit, Bench and ConvertHistory are handled before loading the model.")
+        }
+    }
+    g.write_profile();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// What a `Capture` sink has received.
+    #[derive(Default)]
+    struct Captured {
+        chunks: Vec<String>,
+        finished: usize
+    }
+
+    /// A sink recording the chunks it receives, shared with the test which reads them.
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Captured>>);
+
+    impl OutputSink for Capture {
+        fn push(&mut self, text: &str) {
+            self.0.borrow_mut().chunks.push(text.to_owned());
+        }
+
+        fn finish(&mut self) {
+            self.0.borrow_mut().finished += 1;
+        }
+    }
+
+    #[test]
+    fn fan_out_sends_every_chunk_to_every_sink() {
+        let (a, b) = (Capture::default(), Capture::default());
+        let mut out = FanOut { sinks: vec![Box::new(a.clone()), Box::new(b.clone())] };
+        out.push("Hello");
+        out.push("");
+        out.push(", world");
+        out.finish();
+        for sink in [a, b] {
+            let captured = sink.0.borrow();
+            assert_eq!(captured.chunks, ["Hello", ", world"]);
+            assert_eq!(captured.finished, 1);
+        }
+    }
+
+    /// Generates a fixed completion, passing it to the streaming callback, if any, in `pieces`.
+    struct FakeGenerator {
+        pieces: Vec<&'static str>
+    }
+
+    impl TextGenerator for FakeGenerator {
+        fn complete(&mut self, _prompt: &str, _cancel: &AtomicBool, on_text: Option<&mut dyn FnMut(&str)>) -> Result<Completion, GenError> {
+            if let Some(cb) = on_text {
+                self.pieces.iter().for_each(|p| cb(p));
             }
+            Ok(Completion {
+                text: self.pieces.concat(),
+                finish_reason: FinishReason::Eos,
+                prompt_tokens: 1,
+                generated_tokens: self.pieces.len(),
+                elapsed: Duration::ZERO,
+                token_latencies: Vec::new()
+            })
         }
     }
+
+    /// Respond to a prompt with a fake generator, returning what the output received and the text shown.
+    fn respond_with(options: &[&str]) -> (Vec<String>, String) {
+        let args = CliArgs::try_parse_from(["vocllm"].iter().chain(options).chain(&["single", "unused"])).unwrap();
+        let sink = Capture::default();
+        let mut out = FanOut { sinks: vec![Box::new(sink.clone())] };
+        let mut g = FakeGenerator { pieces: vec!["Hello", ",", " world"] };
+        let (_, shown) = respond(&mut g, &args, &mut out, "prompt").unwrap();
+        let chunks = sink.0.borrow().chunks.clone();
+        (chunks, shown)
+    }
+
+    #[test]
+    fn no_stream_writes_once() {
+        let (chunks, shown) = respond_with(&["--no-stream"]);
+        assert_eq!(chunks, ["Hello, world"]);
+        assert_eq!(shown, "Hello, world");
+    }
+
+    #[test]
+    fn streaming_writes_each_piece() {
+        let (chunks, shown) = respond_with(&[]);
+        assert_eq!(chunks, ["Hello", ",", " world"]);
+        assert_eq!(shown, "Hello, world");
+    }
 }
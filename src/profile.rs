@@ -0,0 +1,108 @@
+//! Module for recording the duration of each stage of loading and generation, as a Chrome trace (viewable in chrome://tracing or Perfetto),
+//! and for querying the memory usage of the process.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+use serde_json::{json, Value};
+
+/// Records spans of time, and writes them as Chrome trace "complete" events.
+/// On GPU, kernels run asynchronously, so time may show up in the span which first reads results back (sampling), rather than in the forward pass.
+pub struct Profiler {
+	path: String,
+	origin: Instant,
+	events: Vec<Value>
+}
+
+impl Profiler {
+	pub fn new(path: &str) -> Self {
+		Self { path: path.to_owned(), origin: Instant::now(), events: Vec::new() }
+	}
+
+	/// Record a span from `start` until now, in category `cat`, with optional arguments shown alongside it.
+	pub fn record(&mut self, name: &str, cat: &str, start: Instant, args: Option<Value>) {
+		let ts = start.saturating_duration_since(self.origin).as_secs_f64() * 1e6;
+		let dur = start.elapsed().as_secs_f64() * 1e6;
+		let mut event = json!({ "name": name, "cat": cat, "ph": "X", "ts": ts, "dur": dur, "pid": 1, "tid": 1 });
+		if let Some(args) = args {
+			event["args"] = args;
+		}
+		self.events.push(event);
+	}
+
+	/// Write all recorded events to the trace file. Failures are logged, since profiling should never abort a run.
+	pub fn write(&self) {
+		let result = File::create(&self.path).and_then(|f| {
+			let mut out = BufWriter::new(f);
+			serde_json::to_writer(&mut out, &json!({ "traceEvents": self.events, "displayTimeUnit": "ms" }))?;
+			out.flush()
+		});
+		match result {
+			Ok(()) => debug!("Wrote {} trace events to {}", self.events.len(), self.path),
+			Err(e) => error!("Failed to write profile to {}, cause: \"{}\"", self.path, e)
+		}
+	}
+}
+
+/// Peak resident set size of this process so far, in bytes, if the platform reports it.
+/// Memory allocated on a GPU is not included.
+#[cfg(unix)]
+pub fn peak_rss() -> Option<u64> {
+	let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+	// getrusage only writes to the struct it is given.
+	if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+		return None;
+	}
+	let max_rss = unsafe { usage.assume_init() }.ru_maxrss as u64;
+	// macOS reports bytes, other unixes kilobytes.
+	Some(if cfg!(target_os = "macos") { max_rss } else { max_rss * 1024 })
+}
+
+/// Peak working set of this process so far, in bytes. Memory allocated on a GPU is not included.
+#[cfg(windows)]
+pub fn peak_rss() -> Option<u64> {
+	// Layout of PROCESS_MEMORY_COUNTERS; only the peak working set is read.
+	#[repr(C)]
+	#[allow(dead_code)]
+	struct ProcessMemoryCounters {
+		cb: u32,
+		page_fault_count: u32,
+		peak_working_set_size: usize,
+		working_set_size: usize,
+		quota_peak_paged_pool_usage: usize,
+		quota_paged_pool_usage: usize,
+		quota_peak_non_paged_pool_usage: usize,
+		quota_non_paged_pool_usage: usize,
+		pagefile_usage: usize,
+		peak_pagefile_usage: usize
+	}
+	extern "system" {
+		fn GetCurrentProcess() -> *mut std::ffi::c_void;
+		fn K32GetProcessMemoryInfo(process: *mut std::ffi::c_void, counters: *mut ProcessMemoryCounters, cb: u32) -> i32;
+	}
+	let cb = std::mem::size_of::<ProcessMemoryCounters>() as u32;
+	let mut counters = std::mem::MaybeUninit::<ProcessMemoryCounters>::zeroed();
+	// The pseudo-handle of the current process needs no closing, and the counters are only written.
+	if unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), counters.as_mut_ptr(), cb) } == 0 {
+		return None;
+	}
+	Some(unsafe { counters.assume_init() }.peak_working_set_size as u64)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn peak_rss() -> Option<u64> {
+	None
+}
+
+/// The `p`th percentile (in [0, 100]) of durations sorted in increasing order, by the nearest-rank method. Zero if there are none.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+	let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+	sorted.get(rank.max(1) - 1).or(sorted.last()).copied().unwrap_or_default()
+}
+
+/// Format a byte count in MiB, for logs.
+pub fn format_mib(bytes: Option<u64>) -> String {
+	bytes.map(|b| format!("{:.1} MiB", b as f64 / (1024.0 * 1024.0))).unwrap_or_else(|| "unknown".to_owned())
+}
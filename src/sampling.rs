@@ -0,0 +1,395 @@
+//! Module for sampling strategies which operate directly on logits, as alternatives to candle's `LogitsProcessor`.
+
+use std::collections::{HashMap, HashSet};
+
+use clap::ValueEnum;
+use rand::{distributions::Distribution, rngs::StdRng, RngCore, SeedableRng};
+use rand::distributions::WeightedIndex;
+use rand_chacha::{ChaCha20Rng, ChaCha8Rng};
+
+/// Number of top candidates used by mirostat v1 to estimate the Zipf exponent.
+const MIROSTAT_M: usize = 100;
+
+/// Number of most likely candidates classified by `StructuralTemperature`, since classifying a token means decoding it.
+const STRUCTURAL_CANDIDATES: usize = 40;
+
+/// Random number generators which samplers may be seeded with.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RngAlgo {
+	/// Candle's own top-p/temperature sampler, and `rand`'s `StdRng` for the others.
+	/// `StdRng` is not guaranteed to produce the same stream across versions of `rand`, or platforms.
+	Candle,
+	/// ChaCha with 8 rounds. Portable, and fast.
+	#[value(name = "chacha8")]
+	ChaCha8,
+	/// ChaCha with 20 rounds. Portable.
+	#[value(name = "chacha20")]
+	ChaCha20
+}
+
+/// Seed a random number generator of the chosen algorithm.
+pub fn seeded_rng(algo: RngAlgo, seed: u64) -> Box<dyn RngCore + Send> {
+	match algo {
+		RngAlgo::Candle => Box::new(StdRng::seed_from_u64(seed)),
+		RngAlgo::ChaCha8 => Box::new(ChaCha8Rng::seed_from_u64(seed)),
+		RngAlgo::ChaCha20 => Box::new(ChaCha20Rng::seed_from_u64(seed))
+	}
+}
+
+/// Compute token probabilities from logits, sorted by descending probability.
+fn sorted_probs(logits: &[f32]) -> Vec<(u32, f32)> {
+	let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+	let mut probs: Vec<(u32, f32)> = logits.iter().enumerate().map(|(i, l)| (i as u32, (l - max).exp())).collect();
+	let sum: f32 = probs.iter().map(|(_, p)| p).sum();
+	probs.iter_mut().for_each(|(_, p)| *p /= sum);
+	probs.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+	probs
+}
+
+/// Mask the logits of all tokens except the first `keep` candidates, so they can never be sampled.
+fn retain_candidates(logits: &mut [f32], candidates: &[(u32, f32)], keep: usize) {
+	for (token, _) in &candidates[keep.max(1).min(candidates.len())..] {
+		logits[*token as usize] = f32::NEG_INFINITY;
+	}
+}
+
+/// Tail-free sampling: discard the tail of the distribution, found where the second derivative of the sorted probabilities flattens out.
+/// `z` is the fraction of the (normalized) second derivative mass to keep; `z >= 1` disables the filter.
+pub fn tail_free(logits: &mut [f32], z: f32) {
+	if z >= 1.0 || logits.len() < 3 {
+		return;
+	}
+	let candidates = sorted_probs(logits);
+	let first: Vec<f32> = candidates.windows(2).map(|w| w[0].1 - w[1].1).collect();
+	let second: Vec<f32> = first.windows(2).map(|w| (w[0] - w[1]).abs()).collect();
+	let total: f32 = second.iter().sum();
+	if total <= 0.0 {
+		return;
+	}
+	let mut cumsum = 0.0;
+	let mut keep = candidates.len();
+	for (i, d) in second.iter().enumerate() {
+		cumsum += d / total;
+		if cumsum > z && i >= 1 {
+			keep = i;
+			break;
+		}
+	}
+	retain_candidates(logits, &candidates, keep);
+}
+
+/// Locally typical sampling: keep the tokens whose surprise is closest to the entropy of the distribution, up to a cumulative probability of `p`.
+/// `p >= 1` disables the filter.
+pub fn locally_typical(logits: &mut [f32], p: f32) {
+	if p >= 1.0 {
+		return;
+	}
+	let mut candidates = sorted_probs(logits);
+	let entropy: f32 = -candidates.iter().filter(|(_, q)| *q > 0.0).map(|(_, q)| q * q.ln()).sum::<f32>();
+	let deviation = |q: f32| (-q.ln() - entropy).abs();
+	candidates.sort_by(|(_, a), (_, b)| deviation(*a).total_cmp(&deviation(*b)));
+	let mut cumsum = 0.0;
+	let mut keep = candidates.len();
+	for (i, (_, q)) in candidates.iter().enumerate() {
+		cumsum += q;
+		if cumsum > p {
+			keep = i + 1;
+			break;
+		}
+	}
+	retain_candidates(logits, &candidates, keep);
+}
+
+/// Mirostat sampling, which adaptively truncates the distribution to keep the surprise of sampled tokens close to a target value.
+/// See "Mirostat: A Neural Text Decoding Algorithm that Directly Controls Perplexity" (Basu et al., 2020).
+pub struct Mirostat {
+	version: u8,
+	/// Target surprise.
+	tau: f32,
+	/// Learning rate.
+	eta: f32,
+	/// Running maximum surprise, updated after every sampled token.
+	mu: f32,
+	rng: Box<dyn RngCore + Send>
+}
+
+impl Mirostat {
+	pub fn new(version: u8, tau: f32, eta: f32, rng: Box<dyn RngCore + Send>) -> Self {
+		Self {
+			version,
+			tau,
+			eta,
+			mu: 2.0 * tau,
+			rng
+		}
+	}
+
+	/// Restore the running estimate to its initial value.
+	pub fn reset(&mut self) {
+		self.mu = 2.0 * self.tau;
+	}
+
+	/// Reset the running estimate and replace the random number generator, as though newly created.
+	pub fn reseed(&mut self, rng: Box<dyn RngCore + Send>) {
+		self.reset();
+		self.rng = rng;
+	}
+
+	/// Sample a token from `logits`, scaled by `temperature`, and update the running estimate.
+	pub fn sample(&mut self, logits: &[f32], temperature: f64) -> u32 {
+		let temperature = temperature as f32;
+		let scaled: Vec<f32> = if temperature > 1e-7 {
+			logits.iter().map(|l| l / temperature).collect()
+		} else {
+			logits.to_vec()
+		};
+		let mut candidates = sorted_probs(&scaled);
+
+		let keep = if self.version == 1 {
+			self.v1_top_k(&candidates)
+		} else {
+			// Discard candidates whose surprise exceeds mu, keeping at least the most probable one.
+			candidates.iter().take_while(|(_, p)| -p.log2() <= self.mu).count().max(1)
+		};
+		candidates.truncate(keep);
+
+		let dist = WeightedIndex::new(candidates.iter().map(|(_, p)| *p)).expect("Mirostat candidates have no probability mass");
+		let total: f32 = candidates.iter().map(|(_, p)| p).sum();
+		let (token, p) = candidates[dist.sample(&mut self.rng)];
+		let surprise = -(p / total).log2();
+		self.mu -= self.eta * (surprise - self.tau);
+		token
+	}
+
+	/// Estimate the Zipf exponent of the distribution, and from it the number of candidates to keep.
+	fn v1_top_k(&self, candidates: &[(u32, f32)]) -> usize {
+		let m = MIROSTAT_M.min(candidates.len());
+		let (mut sum_ti_bi, mut sum_ti_sq) = (0.0f32, 0.0f32);
+		for i in 0..m.saturating_sub(1) {
+			let t_i = ((i + 2) as f32 / (i + 1) as f32).ln();
+			let b_i = (candidates[i].1 / candidates[i + 1].1).ln();
+			if b_i.is_finite() {
+				sum_ti_bi += t_i * b_i;
+				sum_ti_sq += t_i * t_i;
+			}
+		}
+		if sum_ti_sq == 0.0 {
+			return 1;
+		}
+		let s_hat = sum_ti_bi / sum_ti_sq;
+		let epsilon_hat = s_hat - 1.0;
+		let n = candidates.len() as f32;
+		let k = ((epsilon_hat * 2f32.powf(self.mu)) / (1.0 - n.powf(-epsilon_hat))).powf(1.0 / s_hat);
+		if k.is_finite() {
+			(k.round() as usize).clamp(1, candidates.len())
+		} else {
+			candidates.len()
+		}
+	}
+}
+
+/// Greedy decoding which, when other tokens are within `eps` of the top logit, samples among just those near-top tokens.
+/// This mostly behaves like argmax, but breaks up the loops pure greedy decoding tends to fall into.
+pub struct GreedyJitter {
+	eps: f32,
+	rng: Box<dyn RngCore + Send>
+}
+
+impl GreedyJitter {
+	pub fn new(eps: f32, rng: Box<dyn RngCore + Send>) -> Self {
+		Self { eps, rng }
+	}
+
+	pub fn reseed(&mut self, rng: Box<dyn RngCore + Send>) {
+		self.rng = rng;
+	}
+
+	pub fn sample(&mut self, logits: &[f32]) -> u32 {
+		let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+		let near: Vec<(u32, f32)> = logits.iter().enumerate()
+			.filter(|(_, l)| max - **l <= self.eps)
+			.map(|(i, l)| (i as u32, (l - max).exp()))
+			.collect();
+		if near.len() == 1 {
+			return near[0].0;
+		}
+		let dist = WeightedIndex::new(near.iter().map(|(_, w)| *w)).expect("Near-top candidates have no probability mass");
+		near[dist.sample(&mut self.rng)].0
+	}
+}
+
+/// The token with the highest logit. Ties go to the lowest token ID, so that greedy decoding picks the same token
+/// whatever the platform or the order in which a device reduces the logits.
+pub fn argmax(logits: &[f32]) -> u32 {
+	logits.iter().enumerate().fold((0, f32::NEG_INFINITY), |best, (i, l)| if *l > best.1 { (i as u32, *l) } else { best }).0
+}
+
+/// Log-probabilities of the tokens, from their logits.
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+	let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+	let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+	logits.iter().map(|l| l - log_sum).collect()
+}
+
+/// The `k` tokens with the highest values, highest first. Ties go to the lowest token ID, as with `argmax`.
+pub fn top_k(values: &[f32], k: usize) -> Vec<(u32, f32)> {
+	let by_value = |a: &u32, b: &u32| values[*b as usize].total_cmp(&values[*a as usize]).then(a.cmp(b));
+	let mut ids: Vec<u32> = (0..values.len() as u32).collect();
+	if k < ids.len() {
+		ids.select_nth_unstable_by(k, by_value);
+		ids.truncate(k);
+	}
+	ids.sort_unstable_by(by_value);
+	ids.into_iter().map(|t| (t, values[t as usize])).collect()
+}
+
+/// Temperature and top-p sampling, like candle's `LogitsProcessor`, but with a caller-chosen random number generator.
+/// Given the same logits and seed, the sampled tokens are the same on every host.
+pub struct TopPSampler {
+	temperature: f32,
+	top_p: Option<f32>,
+	rng: Box<dyn RngCore + Send>
+}
+
+impl TopPSampler {
+	pub fn new(temperature: f64, top_p: Option<f64>, rng: Box<dyn RngCore + Send>) -> Self {
+		Self { temperature: temperature as f32, top_p: top_p.map(|p| p as f32), rng }
+	}
+
+	pub fn sample(&mut self, logits: &[f32]) -> u32 {
+		if self.temperature <= 1e-7 {
+			return argmax(logits);
+		}
+		let scaled: Vec<f32> = logits.iter().map(|l| l / self.temperature).collect();
+		// Sorting is stable, so equally probable candidates stay in token ID order.
+		let mut candidates = sorted_probs(&scaled);
+		if let Some(top_p) = self.top_p {
+			let mut cumsum = 0.0;
+			let keep = candidates.iter().take_while(|(_, p)| {
+				let within = cumsum < top_p;
+				cumsum += p;
+				within
+			}).count();
+			candidates.truncate(keep.max(1));
+		}
+		let dist = WeightedIndex::new(candidates.iter().map(|(_, p)| *p)).expect("Top-p candidates have no probability mass");
+		candidates[dist.sample(&mut self.rng)].0
+	}
+}
+
+/// Blocks repetition of any n-gram which already occurs in the generated text.
+/// Generated n-grams are indexed by their first `n - 1` tokens, so the tokens which would complete a repeat can be found directly.
+pub struct NgramBlocker {
+	n: usize,
+	index: HashMap<Vec<u32>, HashSet<u32>>
+}
+
+impl NgramBlocker {
+	pub fn new(n: usize) -> Self {
+		Self { n, index: HashMap::new() }
+	}
+
+	/// Index the n-gram ending at the last of the `generated` tokens.
+	pub fn push(&mut self, generated: &[u32]) {
+		if let Some(ngram) = generated.len().checked_sub(self.n).map(|start| &generated[start..]) {
+			let (last, prefix) = ngram.split_last().expect("n-gram size must be positive");
+			self.index.entry(prefix.to_vec()).or_default().insert(*last);
+		}
+	}
+
+	/// Mask the logits of tokens which would complete an n-gram already present in the `generated` tokens.
+	pub fn block(&self, generated: &[u32], logits: &mut [f32]) {
+		let Some(start) = (generated.len() + 1).checked_sub(self.n) else {
+			return;
+		};
+		if let Some(banned) = self.index.get(&generated[start..]) {
+			for token in banned {
+				logits[*token as usize] = f32::NEG_INFINITY;
+			}
+		}
+	}
+}
+
+/// Samples "structural" tokens (whitespace and punctuation) at a temperature of their own, to keep formatting crisp while content is sampled freely.
+/// Only the most likely candidates are classified, each by decoding it once; less likely tokens keep the main temperature.
+pub struct StructuralTemperature {
+	temperature: f64,
+	/// Characters which, besides whitespace, make up structural tokens. When unset, ASCII punctuation.
+	chars: Option<String>,
+	/// Whether each token classified so far is structural.
+	classes: HashMap<u32, bool>
+}
+
+impl StructuralTemperature {
+	pub fn new(temperature: f64, chars: Option<String>) -> Self {
+		Self { temperature, chars, classes: HashMap::new() }
+	}
+
+	fn is_structural(&self, piece: &str) -> bool {
+		!piece.is_empty() && piece.chars().all(|c| c.is_whitespace() || match &self.chars {
+			Some(chars) => chars.contains(c),
+			None => c.is_ascii_punctuation()
+		})
+	}
+
+	/// Rescale the logits of structural tokens among the top candidates, relative to the largest logit, so that once the sampler
+	/// divides all logits by `temperature`, structural tokens are weighted as if by the structural temperature instead.
+	/// `decode` gives the text of a token.
+	pub fn apply(&mut self, logits: &mut [f32], temperature: f64, decode: impl Fn(u32) -> Option<String>) {
+		if temperature <= 1e-7 {
+			return;
+		}
+		let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+		let scale = (temperature / self.temperature.max(1e-7)) as f32;
+		let mut order: Vec<u32> = (0..logits.len() as u32).filter(|t| logits[*t as usize].is_finite()).collect();
+		// Only which tokens are the top candidates matters, not their order, so the vocabulary is partitioned rather than sorted.
+		if order.len() > STRUCTURAL_CANDIDATES {
+			order.select_nth_unstable_by(STRUCTURAL_CANDIDATES, |a, b| logits[*b as usize].total_cmp(&logits[*a as usize]));
+			order.truncate(STRUCTURAL_CANDIDATES);
+		}
+		for token in order {
+			let structural = match self.classes.get(&token) {
+				Some(s) => *s,
+				None => {
+					let s = decode(token).is_some_and(|piece| self.is_structural(&piece));
+					self.classes.insert(token, s);
+					s
+				}
+			};
+			if structural {
+				let l = &mut logits[token as usize];
+				*l = max + (*l - max) * scale;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LOGITS: [f32; 6] = [1.0, 2.5, 0.5, 2.5, -1.0, 1.5];
+
+	fn draw(algo: RngAlgo, seed: u64) -> Vec<u32> {
+		let mut sampler = TopPSampler::new(1.0, Some(0.95), seeded_rng(algo, seed));
+		(0..16).map(|_| sampler.sample(&LOGITS)).collect()
+	}
+
+	#[test]
+	fn greedy_ties_go_to_the_lowest_id() {
+		assert_eq!(argmax(&LOGITS), 1);
+		assert_eq!(argmax(&[0.0, 3.0, 3.0, 3.0]), 1);
+		assert_eq!(argmax(&[f32::NEG_INFINITY, f32::NEG_INFINITY]), 0);
+		// Sampling at temperature 0 is greedy, whatever the random stream.
+		let mut sampler = TopPSampler::new(0.0, None, seeded_rng(RngAlgo::ChaCha8, 1));
+		assert!((0..8).all(|_| sampler.sample(&LOGITS) == 1));
+	}
+
+	/// The draws are pinned, so that a change in the random stream (or the sampling built on it) across versions or hosts fails here.
+	#[test]
+	fn portable_samplers_are_reproducible() {
+		assert_eq!(draw(RngAlgo::ChaCha8, 7), [1, 1, 1, 1, 1, 3, 1, 3, 1, 3, 5, 1, 1, 1, 1, 5]);
+		assert_eq!(draw(RngAlgo::ChaCha20, 7), [1, 1, 5, 3, 3, 2, 1, 1, 3, 1, 3, 3, 3, 3, 3, 5]);
+		assert_ne!(draw(RngAlgo::ChaCha8, 7), draw(RngAlgo::ChaCha8, 8));
+	}
+}
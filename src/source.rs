@@ -0,0 +1,60 @@
+//! Module for reading text (system prompts, context) from a file, or from an `http(s)://` URL when built with the `reqwest` feature.
+
+use std::io;
+
+/// Fetched content younger than this is read from the cache instead of being fetched again.
+#[cfg(feature = "reqwest")]
+const CACHE_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Whether `source` is a URL rather than a path.
+pub fn is_url(source: &str) -> bool {
+	source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Read the text at `source`, a path or URL.
+pub fn read_to_string(source: &str) -> io::Result<String> {
+	if is_url(source) {
+		fetch(source)
+	} else {
+		std::fs::read_to_string(source)
+	}
+}
+
+#[cfg(not(feature = "reqwest"))]
+fn fetch(url: &str) -> io::Result<String> {
+	Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot fetch {}, as vocllm was built without the reqwest feature", url)))
+}
+
+/// Fetch `url`, through a cache in the temporary directory, so that repeated runs (such as REPL sessions) do not refetch it.
+/// When fetching fails, stale cached content is used, if any.
+#[cfg(feature = "reqwest")]
+fn fetch(url: &str) -> io::Result<String> {
+	use log::{debug, warn};
+	use crate::llmcall::{fnv1a, FNV_OFFSET_BASIS};
+
+	// A stable hash, as for the completion cache, so that cached copies are found again after a toolchain upgrade.
+	let cache = std::env::temp_dir().join(format!("vocllm-{:016x}.txt", fnv1a(FNV_OFFSET_BASIS, url.as_bytes())));
+	let age = std::fs::metadata(&cache).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+	if age.is_some_and(|a| a < CACHE_MAX_AGE) {
+		debug!("Reading {} from cache {}", url, cache.display());
+		return std::fs::read_to_string(&cache);
+	}
+
+	let fetched = reqwest::blocking::get(url)
+		.and_then(|r| r.error_for_status())
+		.and_then(|r| r.text())
+		.map_err(io::Error::other);
+	match fetched {
+		Ok(text) => {
+			if let Err(e) = std::fs::write(&cache, &text) {
+				warn!("Failed to cache {} in {}, cause: \"{}\"", url, cache.display(), e);
+			}
+			Ok(text)
+		},
+		Err(e) if age.is_some() => {
+			warn!("Failed to fetch {}, cause: \"{}\"; using the copy cached in {}", url, e, cache.display());
+			std::fs::read_to_string(&cache)
+		},
+		Err(e) => Err(e)
+	}
+}
@@ -0,0 +1,73 @@
+//! Module for streaming generation into a buffer which the caller polls, for imperative UIs which redraw in a loop of their own
+//! rather than taking callbacks. Generation runs on a thread of its own, owning the generator until it is joined.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::llmcall::{Completion, GenError, QuantizedTextGenerator};
+
+/// A generation running on another thread, whose text accumulates until polled.
+pub struct StreamHandle {
+	text: Arc<Mutex<String>>,
+	done: Arc<AtomicBool>,
+	cancel: Arc<AtomicBool>,
+	thread: Option<JoinHandle<(QuantizedTextGenerator, Result<Completion, GenError>)>>
+}
+
+impl StreamHandle {
+	/// Start generating a completion for `prompt` on a new thread. Dropping the handle cancels generation.
+	pub fn spawn(mut generator: QuantizedTextGenerator, prompt: String) -> Self {
+		let text = Arc::new(Mutex::new(String::new()));
+		let done = Arc::new(AtomicBool::new(false));
+		let cancel = Arc::new(AtomicBool::new(false));
+		let thread = {
+			let (text, done, cancel) = (text.clone(), done.clone(), cancel.clone());
+			std::thread::spawn(move || {
+				let result = generator.talk_and_map_cancellable(&prompt, &cancel, |piece| lock(&text).push_str(piece));
+				// Set only once all text is in the buffer, so that a poll which sees it also takes the last piece.
+				done.store(true, Ordering::Release);
+				(generator, result)
+			})
+		};
+		Self { text, done, cancel, thread: Some(thread) }
+	}
+
+	/// Take the text generated since the last poll, and whether generation has ended.
+	/// Once this returns `true`, no more text follows, and `join` returns without waiting.
+	pub fn poll(&self) -> (String, bool) {
+		// Read before taking the text, so that no text arrives between the two once done.
+		let done = self.done.load(Ordering::Acquire);
+		(std::mem::take(&mut *lock(&self.text)), done)
+	}
+
+	/// Whether generation has ended. Text may remain to be polled.
+	pub fn is_done(&self) -> bool {
+		self.done.load(Ordering::Acquire)
+	}
+
+	/// Ask generation to stop early. The completion then ends with `FinishReason::Cancelled`.
+	pub fn cancel(&self) {
+		self.cancel.store(true, Ordering::Relaxed);
+	}
+
+	/// Wait for generation to end, returning the generator for further use, and the completion, whose text is the whole output
+	/// (whether or not it was polled).
+	pub fn join(mut self) -> (QuantizedTextGenerator, Result<Completion, GenError>) {
+		let thread = self.thread.take().expect("Generation thread is only taken by join");
+		thread.join().expect("Generation thread panicked")
+	}
+}
+
+impl Drop for StreamHandle {
+	fn drop(&mut self) {
+		if self.thread.is_some() {
+			self.cancel();
+		}
+	}
+}
+
+/// Lock the text buffer. A panic while appending leaves at worst a partial piece, so poisoning is ignored.
+fn lock(text: &Mutex<String>) -> std::sync::MutexGuard<'_, String> {
+	text.lock().unwrap_or_else(|e| e.into_inner())
+}
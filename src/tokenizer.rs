@@ -0,0 +1,49 @@
+//! Module abstracting the tokenizer, so that the generator is not tied to the HuggingFace `tokenizers` crate.
+//! Alternative tokenizers (e.g. SentencePiece, or one built from the vocabulary embedded in a GGUF) implement [`TextTokenizer`].
+
+use std::ops::Deref;
+
+use tokenizers::Tokenizer;
+
+/// Error of a tokenizer. Implementations other than the HuggingFace one box their own errors into it.
+pub type TokenizerError = tokenizers::Error;
+
+/// Conversion between text and the token IDs of a model's vocabulary.
+pub trait TextTokenizer {
+	/// Encode `text` into token IDs, adding the tokenizer's special tokens (e.g. BOS) when `add_special_tokens` is set.
+	fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, TokenizerError>;
+
+	/// Decode token IDs into text, leaving out special tokens when `skip_special_tokens` is set.
+	fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String, TokenizerError>;
+
+	/// Number of tokens in the vocabulary, including added (special) tokens.
+	fn vocab_size(&self) -> usize;
+
+	/// ID of the token whose text is exactly `token`, if the vocabulary has one.
+	fn token_to_id(&self, token: &str) -> Option<u32>;
+
+	/// Text of the token `id`, if the vocabulary has it.
+	fn id_to_token(&self, id: u32) -> Option<String>;
+}
+
+impl TextTokenizer for Tokenizer {
+	fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, TokenizerError> {
+		self.deref().encode(text, add_special_tokens).map(|e| e.get_ids().to_vec())
+	}
+
+	fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String, TokenizerError> {
+		self.deref().decode(ids, skip_special_tokens)
+	}
+
+	fn vocab_size(&self) -> usize {
+		self.get_vocab_size(true)
+	}
+
+	fn token_to_id(&self, token: &str) -> Option<u32> {
+		self.deref().token_to_id(token)
+	}
+
+	fn id_to_token(&self, id: u32) -> Option<String> {
+		self.deref().id_to_token(id)
+	}
+}
@@ -0,0 +1,119 @@
+//! Module for speech synthesis of generated text, through providers chosen by name with `--ttsopt <provider>/<data>`.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// A speech synthesis backend.
+pub trait TtsProvider {
+	/// Speak `text`, returning once it has been spoken.
+	fn speak(&mut self, text: &str) -> io::Result<()>;
+}
+
+/// A registered provider: its name, a description of its data, and a constructor taking that data.
+struct Registration {
+	name: &'static str,
+	data: &'static str,
+	make: fn(&str) -> Result<Box<dyn TtsProvider>, String>
+}
+
+const PROVIDERS: &[Registration] = &[
+	Registration { name: "sapi", data: "voice name, e.g. ZIRA (Windows only)", make: Sapi::make },
+	Registration { name: "espeak", data: "voice, e.g. en-us", make: Espeak::make },
+	Registration { name: "cmd", data: "command line of a program which reads text to speak from stdin", make: Exec::make }
+];
+
+/// Create the provider for a `<provider>/<data>` option string.
+pub fn from_option(opt: &str) -> Result<Box<dyn TtsProvider>, String> {
+	let Some((name, data)) = opt.split_once('/') else {
+		return Err(format!("tts option \"{}\" is not of the form <provider>/<data>", opt));
+	};
+	let Some(r) = PROVIDERS.iter().find(|r| r.name == name) else {
+		let known: Vec<String> = PROVIDERS.iter().map(|r| format!("{}/<{}>", r.name, r.data)).collect();
+		return Err(format!("unknown tts provider \"{}\", expected one of: {}", name, known.join(", ")));
+	};
+	(r.make)(data)
+}
+
+/// Run `command` with `text` written to its standard input, and wait for it to exit.
+fn pipe_to(command: &mut Command, text: &str) -> io::Result<()> {
+	let mut child = command.stdin(Stdio::piped()).stdout(Stdio::null()).spawn()?;
+	// Dropping stdin after writing closes it, so the child sees the end of the text.
+	child.stdin.take().expect("Child stdin is piped").write_all(text.as_bytes())?;
+	let status = child.wait()?;
+	if status.success() {
+		Ok(())
+	} else {
+		Err(io::Error::other(format!("tts command exited with {}", status)))
+	}
+}
+
+/// Windows' SAPI voices, through PowerShell's System.Speech.
+struct Sapi {
+	voice: String
+}
+
+impl Sapi {
+	fn make(voice: &str) -> Result<Box<dyn TtsProvider>, String> {
+		if voice.contains(['\'', '"', '`', '$']) {
+			return Err(format!("invalid sapi voice name \"{}\"", voice));
+		}
+		Ok(Box::new(Sapi { voice: voice.to_owned() }))
+	}
+}
+
+impl TtsProvider for Sapi {
+	fn speak(&mut self, text: &str) -> io::Result<()> {
+		// The first installed voice whose name contains the given one is selected, so "ZIRA" matches "Microsoft Zira Desktop".
+		let script = format!(
+			"Add-Type -AssemblyName System.Speech; \
+			$s = New-Object System.Speech.Synthesis.SpeechSynthesizer; \
+			$v = $s.GetInstalledVoices() | Where-Object {{ $_.VoiceInfo.Name -like '*{}*' }} | Select-Object -First 1; \
+			if ($v) {{ $s.SelectVoice($v.VoiceInfo.Name) }}; \
+			$s.Speak([Console]::In.ReadToEnd())",
+			self.voice
+		);
+		pipe_to(Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", &script]), text)
+	}
+}
+
+/// The espeak (or espeak-ng) speech synthesizer.
+struct Espeak {
+	voice: String
+}
+
+impl Espeak {
+	fn make(voice: &str) -> Result<Box<dyn TtsProvider>, String> {
+		Ok(Box::new(Espeak { voice: voice.to_owned() }))
+	}
+}
+
+impl TtsProvider for Espeak {
+	fn speak(&mut self, text: &str) -> io::Result<()> {
+		let mut command = Command::new("espeak");
+		if !self.voice.is_empty() {
+			command.args(["-v", &self.voice]);
+		}
+		pipe_to(command.arg("--stdin"), text)
+	}
+}
+
+/// Any program which reads the text to speak from stdin, such as `piper --model voice.onnx --output-raw | aplay` wrapped in a script.
+/// The command line is split on whitespace, without any shell quoting.
+struct Exec {
+	program: String,
+	args: Vec<String>
+}
+
+impl Exec {
+	fn make(command_line: &str) -> Result<Box<dyn TtsProvider>, String> {
+		let mut parts = command_line.split_whitespace().map(|s| s.to_owned());
+		let program = parts.next().ok_or_else(|| "cmd tts provider requires a command".to_owned())?;
+		Ok(Box::new(Exec { program, args: parts.collect() }))
+	}
+}
+
+impl TtsProvider for Exec {
+	fn speak(&mut self, text: &str) -> io::Result<()> {
+		pipe_to(Command::new(&self.program).args(&self.args), text)
+	}
+}